@@ -1,15 +1,24 @@
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::io::{BufRead as _, Read, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::{cmp, fs, io, str};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use std::{cmp, fmt, fs, io, str};
 
 use anyhow::Context as _;
 use bstr::BStr;
 use clap::Parser as _;
+use signal_hook::consts::{SIGINT, SIGTERM};
 use tracing_subscriber::prelude::*;
 
-use crate::keymap::{LAYER_DATA_LEN, PROFILE_DATA_LEN};
-use crate::{keymap, layout, scancode};
+use crate::document::ProfileDocument;
+use crate::graph::LayerGraph;
+use crate::keymap::{Layer, LAYER_DATA_LEN, PROFILE_DATA_LEN};
+use crate::profile::{Profile, Region};
+use crate::simulate::{KeyEvent, Simulator};
+use crate::{edit_script, hidraw, include, keymap, layout, lint, scancode, schema};
 
 const GET_PRODUCT_NAME: u16 = 0x1001;
 const GET_KEYBOARD_LAYOUT: u16 = 0x1002;
@@ -35,6 +44,34 @@ enum Command {
     ReadProfile(ReadProfileArgs),
     WriteProfile(WriteProfileArgs),
     ShowProfile(ShowProfileArgs),
+    Diff(DiffArgs),
+    Convert(ConvertArgs),
+    #[command(alias = "check")]
+    Lint(LintArgs),
+    Simulate(SimulateArgs),
+    Where(WhereArgs),
+    SetKey(SetKeyArgs),
+    Fmt(FmtArgs),
+    Migrate(MigrateArgs),
+    Schema(SchemaArgs),
+    SetMetadata(SetMetadataArgs),
+    #[command(subcommand)]
+    Remap(RemapCommand),
+    ApplyEdits(ApplyEditsArgs),
+    #[command(subcommand)]
+    Profile(ProfileCommand),
+    Doctor(DoctorArgs),
+    UdevRule(UdevRuleArgs),
+}
+
+#[derive(Clone, Debug, clap::Args)]
+struct InputArgs {
+    /// Input file [default: stdin]
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+    /// Input file format [default: auto-detect by file extension and content]
+    #[arg(long, value_enum, default_value_t = InputFormat::Auto, hide_default_value = true)]
+    input_format: InputFormat,
 }
 
 #[derive(Clone, Debug, clap::Args)]
@@ -42,6 +79,14 @@ struct ConnectionArgs {
     /// Path to device file to communicate over
     #[arg(long, default_value = "/dev/hidraw1")]
     device: PathBuf,
+    /// Skip checking that the device is the HHKB Studio interface
+    #[arg(long)]
+    no_verify_device: bool,
+    /// Number of data bytes to read per message [default: auto-detect]
+    ///
+    /// Writes always transfer 26 bytes per message.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=MAX_DATA_CHUNK_LEN as i64))]
+    chunk_size: Option<u16>,
 }
 
 pub fn run() -> anyhow::Result<()> {
@@ -55,6 +100,25 @@ pub fn run() -> anyhow::Result<()> {
         Command::ReadProfile(args) => run_read_profile(args),
         Command::WriteProfile(args) => run_write_profile(args),
         Command::ShowProfile(args) => run_show_profile(args),
+        Command::Diff(args) => run_diff(args),
+        Command::Convert(args) => run_convert(args),
+        Command::Lint(args) => run_lint(args),
+        Command::Simulate(args) => run_simulate(args),
+        Command::Where(args) => run_where(args),
+        Command::SetKey(args) => run_set_key(args),
+        Command::Fmt(args) => run_fmt(args),
+        Command::Migrate(args) => run_migrate(args),
+        Command::Schema(args) => run_schema(args),
+        Command::SetMetadata(args) => run_set_metadata(args),
+        Command::Remap(RemapCommand::Swap(args)) => run_remap_swap(args),
+        Command::Remap(RemapCommand::Copy(args)) => run_remap_copy(args),
+        Command::Remap(RemapCommand::Clear(args)) => run_remap_clear(args),
+        Command::Remap(RemapCommand::Replace(args)) => run_remap_replace(args),
+        Command::ApplyEdits(args) => run_apply_edits(args),
+        Command::Profile(ProfileCommand::Copy(args)) => run_profile_copy(args),
+        Command::Profile(ProfileCommand::Swap(args)) => run_profile_swap(args),
+        Command::Doctor(args) => run_doctor(args),
+        Command::UdevRule(args) => run_udev_rule(args),
     }
 }
 
@@ -105,31 +169,53 @@ struct ReadProfileArgs {
     /// Output file [default: stdout]
     #[arg(short, long)]
     output: Option<PathBuf>,
-    /// Output raw binary data
-    #[arg(long)]
+    /// Output format
+    #[arg(long, value_enum, default_value_t = ProfileFormat::Toml)]
+    format: ProfileFormat,
+    /// Output raw binary data (alias for --format=raw)
+    #[arg(long, conflicts_with = "format")]
     raw: bool,
+    /// Add comments describing scancodes to TOML output
+    #[arg(long, conflicts_with = "raw")]
+    annotate: bool,
+    /// Output Fn layers as differences from the base or transparent layer
+    #[arg(long, conflicts_with_all = ["raw", "annotate", "layer"])]
+    sparse: bool,
     /// Profile index to fetch [default: current profile]
     #[arg(long, value_parser = clap::value_parser!(u16).range(0..4))]
     index: Option<u16>,
+    /// Fetch only the specified layer
+    #[arg(long)]
+    layer: Option<Layer>,
 }
 
 fn run_read_profile(args: &ReadProfileArgs) -> anyhow::Result<()> {
     let mut dev = open_device(&args.connection)?;
+    let chunk_lens = data_chunk_lens(&mut dev, &args.connection)?;
+    let range = layer_data_range(args.layer);
     let data = maybe_switch_profile(&mut dev, args.index, |dev| {
-        read_data(dev, 0, PROFILE_DATA_LEN.try_into().unwrap())
+        read_data_range(dev, range, chunk_lens.read)
     })?;
-    let serialized = if args.raw {
-        data
+    let format = if args.raw {
+        ProfileFormat::Raw
     } else {
-        keymap::serialize_to_toml_string(&data).into_bytes()
+        args.format
     };
-    if let Some(path) = &args.output {
-        fs::write(path, serialized)
-            .with_context(|| format!("failed to write {}", path.display()))?;
+    if args.annotate || args.sparse {
+        anyhow::ensure!(
+            format == ProfileFormat::Toml,
+            "--annotate and --sparse are supported only by TOML format"
+        );
+        let serialized = if args.sparse {
+            keymap::serialize_to_sparse_toml_string(&data)
+        } else {
+            let first_layer = args.layer.unwrap_or(Layer::Base);
+            keymap::serialize_to_annotated_toml_string(&data, first_layer)
+        };
+        write_output(args.output.as_deref(), serialized.as_bytes())
     } else {
-        io::stdout().write_all(&serialized)?;
+        write_profile_data(args.output.as_deref(), &data, format)
     }
-    Ok(())
 }
 
 /// Load keymap profile from file
@@ -137,34 +223,282 @@ fn run_read_profile(args: &ReadProfileArgs) -> anyhow::Result<()> {
 struct WriteProfileArgs {
     #[command(flatten)]
     connection: ConnectionArgs,
-    /// Input file [default: stdin]
-    #[arg(short, long)]
-    input: Option<PathBuf>,
+    #[command(flatten)]
+    input: InputArgs,
     /// Profile index to write [default: current profile]
     #[arg(long, value_parser = clap::value_parser!(u16).range(0..4))]
     index: Option<u16>,
+    /// Write only the specified layer (the input contains the single layer)
+    #[arg(long)]
+    layer: Option<Layer>,
+    /// Show changes without writing
+    #[arg(long)]
+    dry_run: bool,
+    /// Show changes and ask for confirmation before writing
+    #[arg(long, conflicts_with = "dry_run")]
+    confirm: bool,
+    /// Write even if the profile has lint errors
+    #[arg(long)]
+    force: bool,
 }
 
 fn run_write_profile(args: &WriteProfileArgs) -> anyhow::Result<()> {
-    let data = read_profile_data(args.input.as_deref())?;
+    let range = layer_data_range(args.layer);
+    let data = read_profile_data(&args.input, range.len())?;
     let mut dev = open_device(&args.connection)?;
-    maybe_switch_profile(&mut dev, args.index, |dev| write_data(dev, 0, &data))?;
+    let chunk_lens = data_chunk_lens(&mut dev, &args.connection)?;
+    let old_profile_data = maybe_switch_profile(&mut dev, args.index, |dev| {
+        read_data_range(dev, 0..PROFILE_DATA_LEN, chunk_lens.read)
+    })?;
+    let mut new_profile_data = old_profile_data.clone();
+    new_profile_data[range.clone()].copy_from_slice(&data);
+    let options = WriteOptions {
+        show_changes: false,
+        dry_run: args.dry_run,
+        confirm: args.confirm,
+        force: args.force,
+    };
+    write_profile_changes(
+        &mut dev,
+        args.index,
+        chunk_lens.write,
+        range,
+        &old_profile_data,
+        &new_profile_data,
+        &options,
+    )
+}
+
+/// How to write profile changes to the keyboard.
+#[derive(Clone, Copy, Debug)]
+struct WriteOptions {
+    /// Print changes even if not previewing
+    show_changes: bool,
+    /// Print changes and lint diagnostics without writing
+    dry_run: bool,
+    /// Print changes and ask for confirmation before writing
+    confirm: bool,
+    /// Write even if the profile has lint errors
+    force: bool,
+}
+
+/// Writes the `range` of the new profile data to the keyboard if changed.
+/// The whole profile data is checked by lint.
+fn write_profile_changes<D: Read + Write>(
+    dev: &mut D,
+    profile_index: Option<u16>,
+    chunk_len: u16,
+    range: Range<usize>,
+    old_profile_data: &[u8],
+    new_profile_data: &[u8],
+    options: &WriteOptions,
+) -> anyhow::Result<()> {
+    let old_data = &old_profile_data[range.clone()];
+    let new_data = &new_profile_data[range.clone()];
+    let preview = options.dry_run || options.confirm;
+    if preview || options.show_changes {
+        print_profile_changes(range.start / LAYER_DATA_LEN, old_data, new_data);
+    }
+    // Lint errors don't stop dry run, which is useful to preview the profile
+    // being fixed.
+    let has_errors = print_lint_diagnostics(new_profile_data);
+    if preview && old_data == new_data {
+        println!("Profile is up to date");
+        return Ok(());
+    }
+    if options.dry_run {
+        return Ok(());
+    }
+    if has_errors && !options.force {
+        anyhow::bail!("profile has lint errors (use --force to write anyway)");
+    }
+    if options.confirm && !prompt_yes_no("Write changes to the keyboard?")? {
+        return Ok(());
+    }
+    // The profile is switched again after confirmation because the user
+    // would otherwise be typing in the target profile.
+    let written_len = maybe_switch_profile(dev, profile_index, |dev| {
+        let start = range.start.try_into().unwrap();
+        write_changed_data(dev, start, old_data, new_data, chunk_len)
+    })?;
+    if written_len == 0 {
+        println!("Profile is up to date");
+    } else {
+        println!("Wrote {written_len} of {} bytes", new_data.len());
+    }
     Ok(())
 }
 
+/// Check keymap profile for common mistakes
+#[derive(Clone, Debug, clap::Args)]
+struct LintArgs {
+    #[command(flatten)]
+    input: InputArgs,
+}
+
+fn run_lint(args: &LintArgs) -> anyhow::Result<()> {
+    let profile_data = read_profile_data(&args.input, PROFILE_DATA_LEN)?;
+    let diagnostics = lint::lint_profile(&profile_data);
+    for diagnostic in &diagnostics {
+        println!("{diagnostic}");
+    }
+    let num_errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == lint::Severity::Error)
+        .count();
+    let num_warnings = diagnostics.len() - num_errors;
+    anyhow::ensure!(
+        num_errors == 0,
+        "{num_errors} error(s), {num_warnings} warning(s)"
+    );
+    if num_warnings > 0 {
+        println!("{num_warnings} warning(s)");
+    }
+    Ok(())
+}
+
+/// Simulate key presses on keymap profile and print the resulting HID usages
+#[derive(Clone, Debug, clap::Args)]
+struct SimulateArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    /// Key events after "--": "+Position" to press, "-Position" to release,
+    /// or "Position" to press and release (e.g. -- +Fn Semicolon -Fn)
+    #[arg(required = true, last = true, value_parser = parse_key_event)]
+    events: Vec<KeyEvent>,
+}
+
+fn run_simulate(args: &SimulateArgs) -> anyhow::Result<()> {
+    let profile_data = read_profile_data(&args.input, PROFILE_DATA_LEN)?;
+    let layers_scancodes = keymap::layers_scancodes(&profile_data);
+    let mut simulator = Simulator::new(&layers_scancodes);
+    for (i, &event) in args.events.iter().enumerate() {
+        let outputs = simulator
+            .handle_event(event)
+            .with_context(|| format!("event #{i} ({event})"))?;
+        let formatted: Vec<_> = outputs.iter().map(|output| output.to_string()).collect();
+        let event = event.to_string();
+        if formatted.is_empty() {
+            println!("{event:<12} (none)");
+        } else {
+            println!("{event:<12} {}", formatted.join(", "));
+        }
+    }
+    let held: Vec<_> = simulator
+        .held_positions()
+        .map(layout::position_name)
+        .collect();
+    if !held.is_empty() {
+        println!("Still held: {}", held.join(", "));
+    }
+    Ok(())
+}
+
+/// Find keys bound to the scancode in all layers
+#[derive(Clone, Debug, clap::Args)]
+#[command(mut_arg("input", |arg| arg.help("Input file [default: read from the device]")))]
+struct WhereArgs {
+    /// Scancode number or label (e.g. "0x00a9", "VolUp")
+    #[arg(value_parser = parse_scancode)]
+    scancode: u16,
+    #[command(flatten)]
+    input: InputArgs,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    /// Profile index to read [default: current profile]
+    #[arg(long, value_parser = clap::value_parser!(u16).range(0..4), conflicts_with = "input")]
+    index: Option<u16>,
+}
+
+fn run_where(args: &WhereArgs) -> anyhow::Result<()> {
+    let profile_data = if args.input.input.is_some() {
+        read_profile_data(&args.input, PROFILE_DATA_LEN)?
+    } else {
+        let mut dev = open_device(&args.connection)?;
+        let chunk_lens = data_chunk_lens(&mut dev, &args.connection)?;
+        maybe_switch_profile(&mut dev, args.index, |dev| {
+            read_data_range(dev, 0..PROFILE_DATA_LEN, chunk_lens.read)
+        })?
+    };
+    let layers_scancodes = keymap::layers_scancodes(&profile_data);
+    let reachable = keymap::reachable_layers(&layers_scancodes);
+    let code = args.scancode;
+    let name = match scancode::scancode_to_label(code) {
+        Some(label) => format!("0x{code:04x} ({label})"),
+        None => format!("0x{code:04x}"),
+    };
+    let mut num_found = 0;
+    let mut num_reachable = 0;
+    for (i, scancodes) in layers_scancodes.iter().enumerate() {
+        let layer = Layer::from_index(i).unwrap();
+        for j in (0..scancodes.len()).filter(|&j| scancodes[j] == code) {
+            let note = if reachable[i] {
+                ""
+            } else {
+                " (unreachable layer)"
+            };
+            println!("{layer}, {}{note}", layout::describe_position(j));
+            num_found += 1;
+            num_reachable += usize::from(reachable[i]);
+        }
+    }
+    anyhow::ensure!(num_found > 0, "{name} isn't bound to any key");
+    anyhow::ensure!(
+        num_reachable > 0,
+        "{name} isn't reachable (bound only in unreachable layers)"
+    );
+    Ok(())
+}
+
+/// Prints lint diagnostics to stderr. Returns true if there are errors.
+fn print_lint_diagnostics(profile_data: &[u8]) -> bool {
+    let diagnostics = lint::lint_profile(profile_data);
+    for diagnostic in &diagnostics {
+        eprintln!("{diagnostic}");
+    }
+    diagnostics
+        .iter()
+        .any(|d| d.severity == lint::Severity::Error)
+}
+
 /// Show keymap profile data
 #[derive(Clone, Debug, clap::Args)]
 struct ShowProfileArgs {
-    /// Input file [default: stdin]
-    #[arg(short, long)]
-    input: Option<PathBuf>,
+    #[command(flatten)]
+    input: InputArgs,
     /// Print each row ignoring physical layout
     #[arg(long)]
     no_layout: bool,
+    /// Print which layers can be activated from which layers
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        default_missing_value = "list",
+        conflicts_with = "no_layout"
+    )]
+    graph: Option<GraphFormat>,
+}
+
+/// Output format of layer graph.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+enum GraphFormat {
+    /// Adjacency list
+    List,
+    /// Graphviz DOT
+    Dot,
 }
 
 fn run_show_profile(args: &ShowProfileArgs) -> anyhow::Result<()> {
-    let profile_data = read_profile_data(args.input.as_deref())?;
+    let profile_data = read_profile_data(&args.input, PROFILE_DATA_LEN)?;
+    if let Some(format) = args.graph {
+        let graph = LayerGraph::new(&keymap::layers_scancodes(&profile_data));
+        match format {
+            GraphFormat::List => print!("{graph}"),
+            GraphFormat::Dot => print!("{}", graph.to_dot()),
+        }
+        return Ok(());
+    }
     for (i, data) in profile_data.chunks_exact(LAYER_DATA_LEN).enumerate() {
         println!("Layer #{i}");
         let scancodes: Vec<_> = data
@@ -194,51 +528,993 @@ fn run_show_profile(args: &ShowProfileArgs) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn read_profile_data(maybe_path: Option<&Path>) -> anyhow::Result<Vec<u8>> {
-    let data = if let Some(path) = &maybe_path {
-        fs::read(path).with_context(|| format!("failed to read {}", path.display()))?
+/// Show keys changed between keymap profiles
+#[derive(Clone, Debug, clap::Args)]
+struct DiffArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    /// Profile file to compare the input against
+    base: PathBuf,
+}
+
+fn run_diff(args: &DiffArgs) -> anyhow::Result<()> {
+    let base_input = InputArgs {
+        input: Some(args.base.clone()),
+        input_format: InputFormat::Auto,
+    };
+    let base_data = read_profile_data(&base_input, PROFILE_DATA_LEN)?;
+    let data = read_profile_data(&args.input, PROFILE_DATA_LEN)?;
+    print_profile_changes(0, &base_data, &data);
+    Ok(())
+}
+
+/// Convert keymap profile file to another format
+#[derive(Clone, Debug, clap::Args)]
+struct ConvertArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    /// Output file [default: stdout]
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Output format
+    #[arg(long, value_enum)]
+    format: ProfileFormat,
+}
+
+fn run_convert(args: &ConvertArgs) -> anyhow::Result<()> {
+    let data = parse_profile_input(&args.input)?;
+    write_profile_data(args.output.as_deref(), &data, args.format)
+}
+
+/// Change key in TOML profile file, preserving comments and formatting
+#[derive(Clone, Debug, clap::Args)]
+struct SetKeyArgs {
+    /// Profile file to edit in place
+    file: PathBuf,
+    /// Layer to edit
+    layer: Layer,
+    /// Key position name (e.g. "Control", "Semicolon")
+    #[arg(value_parser = parse_position)]
+    position: usize,
+    /// Scancode number or label (e.g. "0x00e0", "LControl")
+    #[arg(value_parser = parse_scancode)]
+    scancode: u16,
+}
+
+fn run_set_key(args: &SetKeyArgs) -> anyhow::Result<()> {
+    let mut doc = read_profile_document(&args.file)?;
+    doc.set_scancode(args.layer.index(), args.position, args.scancode)?;
+    write_profile_document(&args.file, &doc)
+}
+
+/// Set metadata in TOML profile file, preserving comments and formatting
+#[derive(Clone, Debug, clap::Args)]
+struct SetMetadataArgs {
+    /// Profile file to edit in place
+    file: PathBuf,
+    /// Metadata key (e.g. "name", "description")
+    key: String,
+    /// Metadata value
+    value: String,
+}
+
+fn run_set_metadata(args: &SetMetadataArgs) -> anyhow::Result<()> {
+    let mut doc = read_profile_document(&args.file)?;
+    doc.set_metadata(&args.key, &args.value)?;
+    write_profile_document(&args.file, &doc)
+}
+
+/// Transform keys in bulk in TOML profile file, preserving comments and
+/// formatting
+#[derive(Clone, Debug, clap::Subcommand)]
+enum RemapCommand {
+    Swap(RemapSwapArgs),
+    Copy(RemapCopyArgs),
+    Clear(RemapClearArgs),
+    Replace(RemapReplaceArgs),
+}
+
+/// Swap two key positions
+#[derive(Clone, Debug, clap::Args)]
+struct RemapSwapArgs {
+    /// Profile file to edit in place
+    file: PathBuf,
+    /// Key position name (e.g. "Control", "Tab")
+    #[arg(value_parser = parse_position)]
+    a: usize,
+    /// Key position name to swap with
+    #[arg(value_parser = parse_position)]
+    b: usize,
+    /// Layer to edit (can be specified multiple times) [default: all layers]
+    #[arg(long = "layer")]
+    layers: Vec<Layer>,
+}
+
+fn run_remap_swap(args: &RemapSwapArgs) -> anyhow::Result<()> {
+    remap_profile_file(&args.file, |profile| {
+        profile.swap_keys(&args.layers, args.a, args.b)
+    })
+}
+
+/// Copy rectangular region of keys to another layer
+#[derive(Clone, Debug, clap::Args)]
+struct RemapCopyArgs {
+    /// Profile file to edit in place
+    file: PathBuf,
+    /// Layer to copy from
+    from: Layer,
+    /// Layer to copy to
+    to: Layer,
+    /// Region between two corner positions (e.g. "r1c11:r3c13", "Q:Slash")
+    #[arg(value_parser = parse_region)]
+    region: Region,
+    /// Top-left position of the destination [default: same as the source]
+    #[arg(long, value_parser = parse_position)]
+    at: Option<usize>,
+}
+
+fn run_remap_copy(args: &RemapCopyArgs) -> anyhow::Result<()> {
+    remap_profile_file(&args.file, |profile| {
+        profile.copy_region(args.from, args.to, &args.region, args.at)
+    })
+}
+
+/// Fill layer with transparent keys
+#[derive(Clone, Debug, clap::Args)]
+struct RemapClearArgs {
+    /// Profile file to edit in place
+    file: PathBuf,
+    /// Layer to clear
+    layer: Layer,
+}
+
+fn run_remap_clear(args: &RemapClearArgs) -> anyhow::Result<()> {
+    remap_profile_file(&args.file, |profile| profile.clear_layer(args.layer))
+}
+
+/// Replace every occurrence of scancode with another
+#[derive(Clone, Debug, clap::Args)]
+struct RemapReplaceArgs {
+    /// Profile file to edit in place
+    file: PathBuf,
+    /// Scancode number or label to replace
+    #[arg(value_parser = parse_scancode)]
+    from: u16,
+    /// Scancode number or label to replace with
+    #[arg(value_parser = parse_scancode)]
+    to: u16,
+    /// Layer to edit (can be specified multiple times) [default: all layers]
+    #[arg(long = "layer")]
+    layers: Vec<Layer>,
+}
+
+fn run_remap_replace(args: &RemapReplaceArgs) -> anyhow::Result<()> {
+    remap_profile_file(&args.file, |profile| {
+        profile.replace_scancode(&args.layers, args.from, args.to)?;
+        Ok(())
+    })
+}
+
+/// Apply edit script to TOML profile file or the device
+///
+/// Script lines: "set <layer> <position> <scancode>", "swap <layer|all>
+/// <position> <position>", "copy <layer> <layer> <position>:<position>
+/// [<position>]", "clear <layer>", "replace <layer|all> <scancode>
+/// <scancode>". Either all edits are applied or none.
+#[derive(Clone, Debug, clap::Args)]
+struct ApplyEditsArgs {
+    /// Edit script file
+    script: PathBuf,
+    /// Profile file to edit in place [default: the device]
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    /// Profile index to edit [default: current profile]
+    #[arg(long, value_parser = clap::value_parser!(u16).range(0..4), conflicts_with = "input")]
+    index: Option<u16>,
+    /// Show changes without writing
+    #[arg(long)]
+    dry_run: bool,
+    /// Write to the device even if the profile has lint errors
+    #[arg(long, conflicts_with = "input")]
+    force: bool,
+}
+
+fn run_apply_edits(args: &ApplyEditsArgs) -> anyhow::Result<()> {
+    let script = fs::read_to_string(&args.script)
+        .with_context(|| format!("failed to read {}", args.script.display()))?;
+    let lines = edit_script::parse_edit_script(&script)
+        .with_context(|| format!("failed to parse {}", args.script.display()))?;
+    let transform = |profile: &mut Profile| {
+        edit_script::apply_edits(profile, &lines)
+            .with_context(|| format!("failed to apply {}", args.script.display()))
+    };
+    if let Some(path) = &args.input {
+        if args.dry_run {
+            let old_data = read_profile_document(path)?.to_data()?;
+            let mut profile = Profile::from_data(&old_data)?;
+            transform(&mut profile)?;
+            print_profile_changes(0, &old_data, &profile.to_data());
+            return Ok(());
+        }
+        return remap_profile_file(path, transform);
+    }
+
+    let mut dev = open_device(&args.connection)?;
+    let chunk_lens = data_chunk_lens(&mut dev, &args.connection)?;
+    let old_data = maybe_switch_profile(&mut dev, args.index, |dev| {
+        read_data_range(dev, 0..PROFILE_DATA_LEN, chunk_lens.read)
+    })?;
+    let mut profile = Profile::from_data(&old_data)?;
+    transform(&mut profile)?;
+    let options = WriteOptions {
+        show_changes: true,
+        dry_run: args.dry_run,
+        confirm: false,
+        force: args.force,
+    };
+    write_profile_changes(
+        &mut dev,
+        args.index,
+        chunk_lens.write,
+        0..PROFILE_DATA_LEN,
+        &old_data,
+        &profile.to_data(),
+        &options,
+    )
+}
+
+/// Applies the transformation to the TOML profile file, and prints changes.
+fn remap_profile_file(
+    path: &Path,
+    transform: impl FnOnce(&mut Profile) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut doc = read_profile_document(path)?;
+    let old_data = doc.to_data()?;
+    let mut profile = Profile::from_data(&old_data)?;
+    transform(&mut profile)?;
+    let new_data = profile.to_data();
+    apply_profile_data(&mut doc, &new_data)?;
+    print_profile_changes(0, &old_data, &new_data);
+    if old_data != new_data {
+        write_profile_document(path, &doc)?;
+    }
+    Ok(())
+}
+
+/// Updates the keys of the document to produce the `profile_data`.
+///
+/// Layers are updated in order so the layers inheriting the preceding layers
+/// get only the keys which still differ.
+fn apply_profile_data(doc: &mut ProfileDocument, profile_data: &[u8]) -> anyhow::Result<()> {
+    for (i, new_layer) in profile_data.chunks_exact(LAYER_DATA_LEN).enumerate() {
+        let cur_data = doc.to_data()?;
+        let cur_layer = &cur_data[i * LAYER_DATA_LEN..][..LAYER_DATA_LEN];
+        let changes = cur_layer.chunks_exact(2).zip(new_layer.chunks_exact(2));
+        for (j, (cur, new)) in changes.enumerate() {
+            if cur != new {
+                doc.set_scancode(i, j, u16::from_be_bytes(new.try_into().unwrap()))?;
+            }
+        }
+    }
+    // Never write the document unless it produces the requested data
+    let cur_data = doc.to_data()?;
+    let mismatch = cur_data
+        .chunks_exact(2)
+        .zip(profile_data.chunks_exact(2))
+        .position(|(cur, new)| cur != new);
+    if let Some(k) = mismatch {
+        let (i, j) = (k / (LAYER_DATA_LEN / 2), k % (LAYER_DATA_LEN / 2));
+        anyhow::bail!(
+            "layer #{i}, {}: change couldn't be applied to the document",
+            layout::describe_position(j)
+        );
+    }
+    Ok(())
+}
+
+/// Reformat TOML profile files in the canonical layout
+#[derive(Clone, Debug, clap::Args)]
+struct FmtArgs {
+    /// Profile files to format in place
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// Don't write files, but exit with error if any file isn't formatted
+    #[arg(long)]
+    check: bool,
+}
+
+fn run_fmt(args: &FmtArgs) -> anyhow::Result<()> {
+    let mut unformatted_paths = Vec::new();
+    for path in &args.files {
+        let serialized = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let mut doc = ProfileDocument::parse(&serialized, include::base_dir_of(Some(path)))
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        doc.format_scancodes()?;
+        if doc.to_string() == serialized {
+            continue;
+        }
+        if args.check {
+            println!("Not formatted: {}", path.display());
+        } else {
+            write_profile_document(path, &doc)?;
+            println!("Formatted: {}", path.display());
+        }
+        unformatted_paths.push(path);
+    }
+    if args.check {
+        anyhow::ensure!(
+            unformatted_paths.is_empty(),
+            "{} file(s) not formatted",
+            unformatted_paths.len()
+        );
+    }
+    Ok(())
+}
+
+/// Upgrade TOML profile files to the current format version
+#[derive(Clone, Debug, clap::Args)]
+struct MigrateArgs {
+    /// Profile files to upgrade in place
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// Don't write files, but exit with error if any file isn't up to date
+    #[arg(long)]
+    check: bool,
+}
+
+fn run_migrate(args: &MigrateArgs) -> anyhow::Result<()> {
+    let mut outdated_paths = Vec::new();
+    for path in &args.files {
+        let mut doc = read_profile_document(path)?;
+        let Some(version) = doc.migrate()? else {
+            continue;
+        };
+        let current_version = keymap::FORMAT_VERSION;
+        if args.check {
+            println!("Outdated: {} (version {version})", path.display());
+        } else {
+            write_profile_document(path, &doc)?;
+            if version == current_version {
+                println!("Added format_version: {}", path.display());
+            } else {
+                println!(
+                    "Migrated: {} (version {version} -> {current_version})",
+                    path.display()
+                );
+            }
+        }
+        outdated_paths.push(path);
+    }
+    if args.check {
+        anyhow::ensure!(
+            outdated_paths.is_empty(),
+            "{} file(s) not up to date",
+            outdated_paths.len()
+        );
+    }
+    Ok(())
+}
+
+/// Output JSON Schema of TOML profile files
+#[derive(Clone, Debug, clap::Args)]
+struct SchemaArgs {
+    /// Output file [default: stdout]
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+fn run_schema(args: &SchemaArgs) -> anyhow::Result<()> {
+    let mut serialized = serde_json::to_string_pretty(&schema::profile_json_schema())?;
+    serialized.push('\n');
+    write_output(args.output.as_deref(), serialized.as_bytes())
+}
+
+fn read_profile_document(path: &Path) -> anyhow::Result<ProfileDocument> {
+    let serialized =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    ProfileDocument::parse(&serialized, include::base_dir_of(Some(path)))
+        .with_context(|| format!("failed to parse {}", path.display()))
+}
+
+fn write_profile_document(path: &Path, doc: &ProfileDocument) -> anyhow::Result<()> {
+    // Make sure the edited document is still valid
+    doc.to_data()?;
+    fs::write(path, doc.to_string()).with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn parse_position(s: &str) -> Result<usize, String> {
+    layout::position_index(s).ok_or_else(|| format!("unknown key position: {s}"))
+}
+
+fn parse_region(s: &str) -> Result<Region, String> {
+    s.parse().map_err(|err| format!("{err}: {s}"))
+}
+
+/// Parses "+Position" (press), "-Position" (release), or "Position" (tap).
+fn parse_key_event(s: &str) -> Result<KeyEvent, String> {
+    if let Some(name) = s.strip_prefix('+') {
+        parse_position(name).map(KeyEvent::Press)
+    } else if let Some(name) = s.strip_prefix('-') {
+        parse_position(name).map(KeyEvent::Release)
+    } else {
+        parse_position(s).map(KeyEvent::Tap)
+    }
+}
+
+fn parse_scancode(s: &str) -> Result<u16, String> {
+    scancode::parse_scancode(s).ok_or_else(|| format!("unknown scancode: {s}"))
+}
+
+/// Manage profiles stored in the keyboard
+#[derive(Clone, Debug, clap::Subcommand)]
+enum ProfileCommand {
+    Copy(ProfileCopyArgs),
+    Swap(ProfileSwapArgs),
+}
+
+/// Copy keymap profile to another profile slot
+#[derive(Clone, Debug, clap::Args)]
+struct ProfileCopyArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    /// Profile index to copy from
+    #[arg(value_parser = clap::value_parser!(u16).range(0..4))]
+    from: u16,
+    /// Profile index to copy to
+    #[arg(value_parser = clap::value_parser!(u16).range(0..4))]
+    to: u16,
+}
+
+fn run_profile_copy(args: &ProfileCopyArgs) -> anyhow::Result<()> {
+    anyhow::ensure!(args.from != args.to, "cannot copy profile to itself");
+    let mut dev = open_device(&args.connection)?;
+    let chunk_lens = data_chunk_lens(&mut dev, &args.connection)?;
+    let range = layer_data_range(None);
+    let data = maybe_switch_profile(&mut dev, Some(args.from), |dev| {
+        read_data_range(dev, range.clone(), chunk_lens.read)
+    })?;
+    let written_len = write_and_verify_profile(&mut dev, args.to, &data, chunk_lens)?;
+    println!(
+        "Copied profile {} to {} ({written_len} bytes written)",
+        args.from, args.to
+    );
+    Ok(())
+}
+
+/// Swap keymap profiles between two profile slots
+#[derive(Clone, Debug, clap::Args)]
+struct ProfileSwapArgs {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    /// Profile index to swap
+    #[arg(value_parser = clap::value_parser!(u16).range(0..4))]
+    a: u16,
+    /// Other profile index to swap
+    #[arg(value_parser = clap::value_parser!(u16).range(0..4))]
+    b: u16,
+}
+
+fn run_profile_swap(args: &ProfileSwapArgs) -> anyhow::Result<()> {
+    anyhow::ensure!(args.a != args.b, "cannot swap profile with itself");
+    let mut dev = open_device(&args.connection)?;
+    let chunk_lens = data_chunk_lens(&mut dev, &args.connection)?;
+    let range = layer_data_range(None);
+    let data_a = maybe_switch_profile(&mut dev, Some(args.a), |dev| {
+        read_data_range(dev, range.clone(), chunk_lens.read)
+    })?;
+    let data_b = maybe_switch_profile(&mut dev, Some(args.b), |dev| {
+        read_data_range(dev, range.clone(), chunk_lens.read)
+    })?;
+    let mut written_len = write_and_verify_profile(&mut dev, args.a, &data_b, chunk_lens)?;
+    written_len +=
+        write_and_verify_profile(&mut dev, args.b, &data_a, chunk_lens).with_context(|| {
+            format!(
+                "failed to write profile {}, which has been copied to {}",
+                args.b, args.a
+            )
+        })?;
+    println!(
+        "Swapped profiles {} and {} ({written_len} bytes written)",
+        args.a, args.b
+    );
+    Ok(())
+}
+
+/// Writes the whole profile `data` to the profile slot, and reads it back to
+/// verify. Returns the number of bytes written.
+fn write_and_verify_profile<D: Read + Write>(
+    dev: &mut D,
+    profile_index: u16,
+    data: &[u8],
+    chunk_lens: ChunkLens,
+) -> anyhow::Result<usize> {
+    let range = layer_data_range(None);
+    let written_len = maybe_switch_profile(dev, Some(profile_index), |dev| {
+        let old_data = read_data_range(dev, range.clone(), chunk_lens.read)?;
+        let written_len = write_changed_data(dev, 0, &old_data, data, chunk_lens.write)?;
+        let new_data = read_data_range(dev, range.clone(), chunk_lens.read)?;
+        if new_data != data {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data read back differs from written",
+            ));
+        }
+        Ok(written_len)
+    })
+    .with_context(|| format!("failed to write profile {profile_index}"))?;
+    Ok(written_len)
+}
+
+/// Prints keys changed from `old_data` to `new_data` per layout row.
+///
+/// The data may be of partial layers starting from `first_layer_index`.
+fn print_profile_changes(first_layer_index: usize, old_data: &[u8], new_data: &[u8]) {
+    let mut num_changed = 0;
+    let old_layers = old_data.chunks_exact(LAYER_DATA_LEN);
+    let new_layers = new_data.chunks_exact(LAYER_DATA_LEN);
+    for (i, (old_layer, new_layer)) in old_layers.zip(new_layers).enumerate() {
+        if old_layer == new_layer {
+            continue;
+        }
+        println!("Layer #{}", first_layer_index + i);
+        let old_rows = old_layer.chunks_exact(30);
+        let new_rows = new_layer.chunks_exact(30);
+        let widths_map = &layout::US_LAYOUT_WIDTHS_MAP;
+        for ((old_row, new_row), widths) in old_rows.zip(new_rows).zip(widths_map) {
+            let old_codes: Vec<_> = old_row
+                .chunks_exact(2)
+                .map(|d| u16::from_be_bytes(d.try_into().unwrap()))
+                .collect();
+            let new_codes: Vec<_> = new_row
+                .chunks_exact(2)
+                .map(|d| u16::from_be_bytes(d.try_into().unwrap()))
+                .collect();
+            let changed: Vec<bool> = old_codes
+                .iter()
+                .zip(&new_codes)
+                .map(|(old, new)| old != new)
+                .collect();
+            if !changed.contains(&true) {
+                continue;
+            }
+            num_changed += changed.iter().filter(|&&b| b).count();
+            for (sign, codes) in [('-', &old_codes), ('+', &new_codes)] {
+                let formatted_codes = layout::format_row(
+                    widths,
+                    codes.iter().zip(&changed).map(|(n, &changed)| {
+                        if changed {
+                            format!("{n:04x}")
+                        } else {
+                            String::new()
+                        }
+                    }),
+                );
+                let formatted_labels = layout::format_row(
+                    widths,
+                    codes.iter().zip(&changed).map(|(n, &changed)| {
+                        changed
+                            .then(|| scancode::scancode_to_label(*n))
+                            .flatten()
+                            .unwrap_or("")
+                    }),
+                );
+                println!("{sign} {formatted_codes}");
+                println!("{sign} {formatted_labels}");
+            }
+        }
+    }
+    println!("{num_changed} key(s) changed");
+}
+
+/// Asks yes/no question on the terminal. Defaults to no.
+fn prompt_yes_no(question: &str) -> anyhow::Result<bool> {
+    // stdin may be used to feed the profile data
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("failed to open terminal")?;
+    write!(tty, "{question} [y/N] ")?;
+    let mut answer = String::new();
+    io::BufReader::new(tty).read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Diagnose connection to the keyboard
+#[derive(Clone, Debug, clap::Args)]
+struct DoctorArgs {
+    /// Seconds to wait for response from each device
+    #[arg(long, default_value_t = 1.0)]
+    timeout: f64,
+}
+
+fn run_doctor(args: &DoctorArgs) -> anyhow::Result<()> {
+    let timeout = Duration::try_from_secs_f64(args.timeout).context("invalid timeout")?;
+    let devices = hidraw::enumerate_devices().context("failed to enumerate hidraw devices")?;
+    if devices.is_empty() {
+        anyhow::bail!("no hidraw device found (is the keyboard connected?)");
+    }
+    let mut num_ok = 0;
+    for info in &devices {
+        println!(
+            "{}: {} ({}, {:04x}:{:04x})",
+            info.path.display(),
+            info.name,
+            info.bus_name(),
+            info.vendor_id,
+            info.product_id
+        );
+        if !info.is_hhkb_studio() {
+            println!("  skipped: not a HHKB Studio");
+            continue;
+        }
+        let file = match OpenOptions::new().read(true).write(true).open(&info.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                println!("  open: {err}");
+                println!("  hint: no read/write permission for the current user.");
+                println!(
+                    "        run `sudo setfacl -m u:$USER:rw {}`,",
+                    info.path.display()
+                );
+                println!(
+                    "        or install the udev rule printed by `hhkb-studio-tools udev-rule`"
+                );
+                continue;
+            }
+            Err(err) => {
+                println!("  open: {err}");
+                continue;
+            }
+        };
+        println!("  open: ok");
+        if let Err(err) = hidraw::verify_interface(&file) {
+            println!("  interface: {err}");
+            println!("  hint: use the other hidraw device of the same keyboard");
+            continue;
+        }
+        println!("  interface: ok");
+        let mut dev = hidraw::TimeoutDevice::new(&file, timeout);
+        match get_simple(&mut dev, GET_PRODUCT_NAME) {
+            Ok(message) => {
+                println!("  product name: {}", truncate_nul_str(&message[3..]));
+                num_ok += 1;
+            }
+            Err(err) => {
+                println!("  product name: {err}");
+            }
+        }
+    }
+    anyhow::ensure!(num_ok > 0, "no usable device found");
+    Ok(())
+}
+
+/// Print udev rules to grant the logged-in user access to the keyboard
+///
+/// Save the output as /etc/udev/rules.d/70-hhkb-studio.rules, and run
+/// `udevadm control --reload && udevadm trigger`.
+#[derive(Clone, Debug, clap::Args)]
+struct UdevRuleArgs {}
+
+fn run_udev_rule(_args: &UdevRuleArgs) -> anyhow::Result<()> {
+    let devices = hidraw::enumerate_devices().context("failed to enumerate hidraw devices")?;
+    let detected = devices.iter().find(|info| info.is_hhkb_studio());
+    let (vendor_id, product_id) = if let Some(info) = detected {
+        println!("# {} ({})", info.name, info.path.display());
+        (info.vendor_id, info.product_id)
+    } else {
+        println!("# HHKB Studio (not detected)");
+        (hidraw::HHKB_VENDOR_ID, hidraw::HHKB_STUDIO_PRODUCT_ID)
+    };
+    // KERNELS matches the HID device "BBBB:VVVV:PPPP.NNNN", which works for
+    // both USB and Bluetooth connections.
+    println!(
+        "KERNEL==\"hidraw*\", SUBSYSTEM==\"hidraw\", \
+         KERNELS==\"*:{vendor_id:04X}:{product_id:04X}.*\", TAG+=\"uaccess\""
+    );
+    Ok(())
+}
+
+/// Profile file format.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+enum ProfileFormat {
+    Toml,
+    Json,
+    Raw,
+}
+
+impl fmt::Display for ProfileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ProfileFormat::Toml => "TOML",
+            ProfileFormat::Json => "JSON",
+            ProfileFormat::Raw => "raw data",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Input file format.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+enum InputFormat {
+    Auto,
+    Toml,
+    Json,
+    Raw,
+}
+
+/// Reads profile data of `len` bytes, which may be of partial layers.
+fn read_profile_data(args: &InputArgs, len: usize) -> anyhow::Result<Vec<u8>> {
+    let data = parse_profile_input(args)?;
+    anyhow::ensure!(
+        data.len() == len,
+        "unexpected number of layers (expected {}, got {})",
+        len / LAYER_DATA_LEN,
+        data.len() / LAYER_DATA_LEN
+    );
+    Ok(data)
+}
+
+/// Reads and parses profile data of any number of layers.
+fn parse_profile_input(args: &InputArgs) -> anyhow::Result<Vec<u8>> {
+    let maybe_path = args.input.as_deref();
+    let data = read_input(maybe_path)?;
+    let (format, reason) = match args.input_format {
+        InputFormat::Auto => detect_profile_format(maybe_path, &data),
+        InputFormat::Toml => (ProfileFormat::Toml, "specified"),
+        InputFormat::Json => (ProfileFormat::Json, "specified"),
+        InputFormat::Raw => (ProfileFormat::Raw, "specified"),
+    };
+    tracing::debug!(?format, reason, "input format");
+    let base_dir = include::base_dir_of(maybe_path);
+    parse_profile_data(&data, format, base_dir).with_context(|| {
+        let name = maybe_path.map_or("<stdin>".into(), |path| path.display().to_string());
+        format!("failed to parse {name} as {format} ({reason})")
+    })
+}
+
+fn read_input(maybe_path: Option<&Path>) -> anyhow::Result<Vec<u8>> {
+    if let Some(path) = &maybe_path {
+        fs::read(path).with_context(|| format!("failed to read {}", path.display()))
     } else {
         let mut buf = Vec::with_capacity(PROFILE_DATA_LEN);
         io::stdin().read_to_end(&mut buf)?;
-        buf
+        Ok(buf)
+    }
+}
+
+/// Guesses profile format by file extension and content. Returns the format
+/// and the reason.
+fn detect_profile_format(maybe_path: Option<&Path>, data: &[u8]) -> (ProfileFormat, &'static str) {
+    let extension = maybe_path.and_then(|path| path.extension()?.to_str());
+    match extension {
+        Some("toml") => return (ProfileFormat::Toml, "detected by file extension"),
+        Some("json") => return (ProfileFormat::Json, "detected by file extension"),
+        Some("bin" | "raw") => return (ProfileFormat::Raw, "detected by file extension"),
+        _ => {}
+    }
+    let Ok(text) = str::from_utf8(data) else {
+        return (ProfileFormat::Raw, "detected by non-text content");
     };
-    if data.contains(&b'\0') {
-        anyhow::ensure!(
-            data.len() == PROFILE_DATA_LEN,
-            "unexpected profile data length"
-        );
-        Ok(data)
+    if text.contains('\0') {
+        (ProfileFormat::Raw, "detected by non-text content")
+    } else if text.trim_start().starts_with('{') {
+        (ProfileFormat::Json, "detected by content")
+    } else if !text.contains("layers")
+        && !data.is_empty()
+        && data.len().is_multiple_of(LAYER_DATA_LEN)
+    {
+        (ProfileFormat::Raw, "detected by data length")
+    } else {
+        (ProfileFormat::Toml, "detected by content")
+    }
+}
+
+/// Parses profile data. Files included by TOML data are resolved relative to
+/// the `base_dir`.
+fn parse_profile_data(
+    data: &[u8],
+    format: ProfileFormat,
+    base_dir: &Path,
+) -> anyhow::Result<Vec<u8>> {
+    match format {
+        ProfileFormat::Toml => {
+            let serialized = str::from_utf8(data).context("invalid profile text")?;
+            include::parse_toml_string_at(serialized, base_dir)
+        }
+        ProfileFormat::Json => {
+            let serialized = str::from_utf8(data).context("invalid profile text")?;
+            keymap::parse_json_string(serialized)
+        }
+        ProfileFormat::Raw => {
+            anyhow::ensure!(
+                !data.is_empty() && data.len().is_multiple_of(LAYER_DATA_LEN),
+                "unexpected profile data length"
+            );
+            Ok(data.to_vec())
+        }
+    }
+}
+
+fn write_profile_data(
+    maybe_path: Option<&Path>,
+    data: &[u8],
+    format: ProfileFormat,
+) -> anyhow::Result<()> {
+    let serialized = match format {
+        ProfileFormat::Toml => keymap::serialize_to_toml_string(data).into_bytes(),
+        ProfileFormat::Json => keymap::serialize_to_json_string(data).into_bytes(),
+        ProfileFormat::Raw => data.to_vec(),
+    };
+    write_output(maybe_path, &serialized)
+}
+
+fn write_output(maybe_path: Option<&Path>, serialized: &[u8]) -> anyhow::Result<()> {
+    if let Some(path) = maybe_path {
+        fs::write(path, serialized)
+            .with_context(|| format!("failed to write {}", path.display()))?;
     } else {
-        let serialized = str::from_utf8(&data).context("invalid profile text")?;
-        keymap::parse_toml_string(serialized)
+        io::stdout().write_all(serialized)?;
     }
+    Ok(())
+}
+
+/// Byte range of the specified layer, or the whole profile data.
+fn layer_data_range(layer: Option<Layer>) -> Range<usize> {
+    layer.map_or(0..PROFILE_DATA_LEN, |layer| layer.data_range())
 }
 
+/// Runs `f` with the specified profile selected, then restores the original
+/// profile.
+///
+/// The original profile is restored even if `f` fails or panics. SIGINT and
+/// SIGTERM interrupt the pending operation (the second signal terminates the
+/// process immediately.)
 fn maybe_switch_profile<D: Read + Write, O>(
     dev: &mut D,
     profile_index: Option<u16>,
-    f: impl FnOnce(&mut D) -> io::Result<O>,
+    f: impl FnOnce(&mut ProfileSwitch<'_, D>) -> io::Result<O>,
 ) -> io::Result<O> {
-    let old_profile_index = if let Some(index) = profile_index {
-        let old_index = get_current_profile(dev)?;
-        set_current_profile(dev, index)?;
-        Some(old_index)
-    } else {
-        None
-    };
-    let res = f(dev);
-    if let Some(index) = old_profile_index {
-        set_current_profile(dev, index)?;
+    let mut switch = ProfileSwitch::new(dev, profile_index)?;
+    let out = f(&mut switch)?;
+    switch.finish()?;
+    Ok(out)
+}
+
+/// Device wrapper that restores the original profile when dropped.
+struct ProfileSwitch<'a, D: Read + Write> {
+    dev: &'a mut D,
+    old_profile_index: Option<u16>,
+    signals: &'static SignalFlags,
+}
+
+impl<'a, D: Read + Write> ProfileSwitch<'a, D> {
+    fn new(dev: &'a mut D, profile_index: Option<u16>) -> io::Result<Self> {
+        let signals = SignalFlags::install()?;
+        signals.arm();
+        let mut switch = ProfileSwitch {
+            dev,
+            old_profile_index: None,
+            signals,
+        };
+        if let Some(index) = profile_index {
+            let old_index = get_current_profile(switch.dev)?;
+            // The profile may have been switched even if the request failed.
+            switch.old_profile_index = Some(old_index);
+            set_current_profile(switch.dev, index)?;
+        }
+        Ok(switch)
+    }
+
+    /// Restores the original profile, and reports error if any.
+    fn finish(mut self) -> io::Result<()> {
+        self.restore()
+    }
+
+    fn restore(&mut self) -> io::Result<()> {
+        if let Some(index) = self.old_profile_index.take() {
+            set_current_profile(self.dev, index)?;
+        }
+        Ok(())
+    }
+
+    fn check_interrupted(&self) -> io::Result<()> {
+        if self.signals.interrupted.load(Ordering::Relaxed) {
+            // Not ErrorKind::Interrupted, which write_all() and read_exact()
+            // would retry forever.
+            Err(io::Error::other("interrupted by signal"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<D: Read + Write> Drop for ProfileSwitch<'_, D> {
+    fn drop(&mut self) {
+        if let Some(index) = self.old_profile_index {
+            tracing::info!(index, "restoring original profile");
+            if let Err(err) = self.restore() {
+                tracing::error!(index, %err, "failed to restore original profile");
+            }
+        }
+        self.signals.disarm();
+    }
+}
+
+impl<D: Read + Write> Read for ProfileSwitch<'_, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_interrupted()?;
+        self.dev.read(buf)
+    }
+}
+
+impl<D: Read + Write> Write for ProfileSwitch<'_, D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_interrupted()?;
+        self.dev.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.dev.flush()
+    }
+}
+
+/// Process-wide SIGINT/SIGTERM state.
+///
+/// While armed, the first signal sets the `interrupted` flag instead of
+/// terminating the process. Otherwise, the default signal action is taken.
+struct SignalFlags {
+    interrupted: Arc<AtomicBool>,
+    pass_through: Arc<AtomicBool>,
+}
+
+impl SignalFlags {
+    fn install() -> io::Result<&'static Self> {
+        static FLAGS: OnceLock<SignalFlags> = OnceLock::new();
+        if let Some(flags) = FLAGS.get() {
+            return Ok(flags);
+        }
+        let flags = SignalFlags {
+            interrupted: Arc::new(AtomicBool::new(false)),
+            pass_through: Arc::new(AtomicBool::new(true)),
+        };
+        for &signal in &[SIGINT, SIGTERM] {
+            signal_hook::flag::register_conditional_default(signal, flags.pass_through.clone())?;
+            signal_hook::flag::register(signal, flags.interrupted.clone())?;
+            signal_hook::flag::register(signal, flags.pass_through.clone())?;
+        }
+        Ok(FLAGS.get_or_init(|| flags))
+    }
+
+    fn arm(&self) {
+        self.interrupted.store(false, Ordering::Relaxed);
+        self.pass_through.store(false, Ordering::Relaxed);
+    }
+
+    fn disarm(&self) {
+        self.pass_through.store(true, Ordering::Relaxed);
     }
-    res
 }
 
 fn open_device(args: &ConnectionArgs) -> anyhow::Result<File> {
-    OpenOptions::new()
+    let file = OpenOptions::new()
         .read(true)
         .write(true)
         .open(&args.device)
-        .with_context(|| format!("failed to open device {}", args.device.display()))
+        .with_context(|| format!("failed to open device {}", args.device.display()))?;
+    if !args.no_verify_device {
+        hidraw::verify_interface(&file).with_context(|| {
+            format!(
+                "refusing to communicate over {} (run `hhkb-studio-tools doctor` to find \
+                 the right device)",
+                args.device.display()
+            )
+        })?;
+    }
+    Ok(file)
 }
 
 #[tracing::instrument(skip(dev))]
@@ -275,16 +1551,130 @@ fn set_current_profile<D: Read + Write>(dev: &mut D, id: u16) -> io::Result<()>
     Ok(())
 }
 
-const MAX_DATA_CHUNK_LEN: u16 = 26; // or 28?
+/// Number of data bytes known to work.
+const DEFAULT_DATA_CHUNK_LEN: u16 = 26;
+/// Number of data bytes that can fit in message.
+const MAX_DATA_CHUNK_LEN: u16 = hidraw::MESSAGE_LEN as u16 - 4;
+
+/// Numbers of data bytes to transfer per read and write message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct ChunkLens {
+    read: u16,
+    write: u16,
+}
+
+/// Determines the numbers of data bytes to transfer per message.
+///
+/// Only the read length is detected or specified, and the detected value is
+/// cached per firmware version. Writes always use [`DEFAULT_DATA_CHUNK_LEN`]
+/// since nothing can tell whether the firmware stores all the bytes written.
+fn data_chunk_lens<D: Read + Write>(
+    dev: &mut D,
+    args: &ConnectionArgs,
+) -> anyhow::Result<ChunkLens> {
+    if let Some(n) = args.chunk_size {
+        return Ok(ChunkLens {
+            read: n,
+            write: DEFAULT_DATA_CHUNK_LEN,
+        });
+    }
+    let message = get_simple(dev, GET_FIRMWARE_VERSION)?;
+    let firmware_version = truncate_nul_str(&message[3..]).to_string();
+    let cache_path = chunk_len_cache_path();
+    let mut cache = cache_path
+        .as_deref()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|data| data.parse::<toml::Table>().ok())
+        .unwrap_or_default();
+    let read_len = if let Some(n) = cache
+        .get(&firmware_version)
+        .and_then(|v| v.as_integer())
+        .and_then(|n| u16::try_from(n).ok())
+        .filter(|n| (1..=MAX_DATA_CHUNK_LEN).contains(n))
+    {
+        tracing::debug!(firmware_version, n, "cached data chunk length");
+        n
+    } else if let Some(n) = probe_data_chunk_len(dev)? {
+        tracing::info!(firmware_version, n, "detected data chunk length");
+        if let Some(path) = &cache_path {
+            cache.insert(firmware_version, i64::from(n).into());
+            let res = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|()| fs::write(path, cache.to_string()));
+            if let Err(err) = res {
+                tracing::warn!(?path, %err, "failed to save data chunk length");
+            }
+        }
+        n
+    } else {
+        tracing::info!(firmware_version, "couldn't detect data chunk length");
+        DEFAULT_DATA_CHUNK_LEN
+    };
+    Ok(ChunkLens {
+        read: read_len,
+        write: DEFAULT_DATA_CHUNK_LEN,
+    })
+}
+
+fn chunk_len_cache_path() -> Option<PathBuf> {
+    let cache_dir = if let Some(dir) = std::env::var_os("XDG_CACHE_HOME") {
+        PathBuf::from(dir)
+    } else {
+        PathBuf::from(std::env::var_os("HOME")?).join(".cache")
+    };
+    Some(cache_dir.join("hhkb-studio-tools/chunk-sizes.toml"))
+}
+
+/// Finds the largest data chunk the firmware returns correctly.
+///
+/// The first bytes of the profile data are read with each candidate length,
+/// and compared with the data read by [`DEFAULT_DATA_CHUNK_LEN`] chunks.
+/// Returns `None` if a candidate couldn't be tested because the data to
+/// compare is all zeros.
+#[tracing::instrument(skip(dev))]
+fn probe_data_chunk_len<D: Read + Write>(dev: &mut D) -> io::Result<Option<u16>> {
+    let n = DEFAULT_DATA_CHUNK_LEN;
+    let mut expected = read_data(dev, 0, n, n)?;
+    expected.extend(read_data(dev, n, MAX_DATA_CHUNK_LEN - n, n)?);
+    let mut all_tested = true;
+    for candidate in (n + 1..=MAX_DATA_CHUNK_LEN).rev() {
+        let extra = &expected[n.into()..candidate.into()];
+        if extra.iter().all(|&c| c == 0) {
+            // Can't tell whether the firmware filled zeros or not.
+            all_tested = false;
+            continue;
+        }
+        let data = read_data(dev, 0, candidate, candidate)?;
+        tracing::debug!(candidate, ?data, "probe");
+        if data == expected[..candidate.into()] {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(all_tested.then_some(n))
+}
+
+fn read_data_range<D: Read + Write>(
+    dev: &mut D,
+    range: Range<usize>,
+    chunk_len: u16,
+) -> io::Result<Vec<u8>> {
+    let start = range.start.try_into().unwrap();
+    let len = range.len().try_into().unwrap();
+    read_data(dev, start, len, chunk_len)
+}
 
 // TODO: Is this a generic function or specific to the profile data?
 #[tracing::instrument(skip(dev))]
-fn read_data<D: Read + Write>(dev: &mut D, start: u16, len: u16) -> io::Result<Vec<u8>> {
+fn read_data<D: Read + Write>(
+    dev: &mut D,
+    start: u16,
+    len: u16,
+    chunk_len: u16,
+) -> io::Result<Vec<u8>> {
     let mut data = Vec::with_capacity(len.into());
-    for offset in (0..len).step_by(MAX_DATA_CHUNK_LEN.into()) {
-        let n: u8 = cmp::min(MAX_DATA_CHUNK_LEN, len - offset)
-            .try_into()
-            .unwrap();
+    for offset in (0..len).step_by(chunk_len.into()) {
+        let n: u8 = cmp::min(chunk_len, len - offset).try_into().unwrap();
         let mut message = [0; 32];
         message[0] = 0x12;
         message[1..3].copy_from_slice(&(start + offset).to_be_bytes());
@@ -300,9 +1690,14 @@ fn read_data<D: Read + Write>(dev: &mut D, start: u16, len: u16) -> io::Result<V
 
 // TODO: Is this a generic function or specific to the profile data?
 #[tracing::instrument(skip(dev, data))]
-fn write_data<D: Read + Write>(dev: &mut D, start: u16, data: &[u8]) -> io::Result<()> {
-    for (i, chunk) in data.chunks(MAX_DATA_CHUNK_LEN.into()).enumerate() {
-        let offset: u16 = MAX_DATA_CHUNK_LEN * u16::try_from(i).unwrap();
+fn write_data<D: Read + Write>(
+    dev: &mut D,
+    start: u16,
+    data: &[u8],
+    chunk_len: u16,
+) -> io::Result<()> {
+    for (i, chunk) in data.chunks(chunk_len.into()).enumerate() {
+        let offset: u16 = chunk_len * u16::try_from(i).unwrap();
         let mut message = [0; 32];
         message[0] = 0x13;
         message[1..3].copy_from_slice(&(start + offset).to_be_bytes());
@@ -317,6 +1712,30 @@ fn write_data<D: Read + Write>(dev: &mut D, start: u16, data: &[u8]) -> io::Resu
     Ok(())
 }
 
+/// Writes only the chunks of `new_data` which differ from `old_data`, returns
+/// the number of bytes written.
+fn write_changed_data<D: Read + Write>(
+    dev: &mut D,
+    start: u16,
+    old_data: &[u8],
+    new_data: &[u8],
+    chunk_len: u16,
+) -> io::Result<usize> {
+    assert_eq!(old_data.len(), new_data.len());
+    let mut written_len = 0;
+    let old_chunks = old_data.chunks(chunk_len.into());
+    let new_chunks = new_data.chunks(chunk_len.into());
+    for (i, (old_chunk, new_chunk)) in old_chunks.zip(new_chunks).enumerate() {
+        if old_chunk == new_chunk {
+            continue;
+        }
+        let offset: u16 = chunk_len * u16::try_from(i).unwrap();
+        write_data(dev, start + offset, new_chunk, chunk_len)?;
+        written_len += new_chunk.len();
+    }
+    Ok(written_len)
+}
+
 fn pack_dipsw(data: &[u8]) -> u8 {
     // dip-sw bit per byte (not packed), from MSB for pretty printing
     data.iter().fold(0, |acc, v| (acc << 1) | (v & 1))
@@ -329,3 +1748,115 @@ fn truncate_nul_str(data: &[u8]) -> &BStr {
         BStr::new(data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Device which returns profile data up to `max_read_len` bytes per
+    /// message.
+    struct FakeDevice {
+        data: Vec<u8>,
+        max_read_len: usize,
+        profile_index: u16,
+        response: Vec<u8>,
+    }
+
+    impl FakeDevice {
+        fn new(data: Vec<u8>, max_read_len: usize) -> Self {
+            FakeDevice {
+                data,
+                max_read_len,
+                profile_index: 0,
+                response: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for FakeDevice {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = cmp::min(buf.len(), self.response.len());
+            buf[..n].copy_from_slice(&self.response[..n]);
+            self.response.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for FakeDevice {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let command = u16::from_be_bytes([buf[1], buf[2]]);
+            let mut message = vec![0; hidraw::MESSAGE_LEN];
+            match buf[0] {
+                0x02 => {
+                    assert_eq!(command, GET_CURRENT_PROFILE);
+                    message[3..5].copy_from_slice(&self.profile_index.to_be_bytes());
+                }
+                0x03 => {
+                    assert_eq!(command, SET_CURRENT_PROFILE);
+                    self.profile_index = u16::from_be_bytes([buf[3], buf[4]]);
+                    self.response.extend(message.clone());
+                }
+                0x12 => {
+                    let start = usize::from(command);
+                    let n = cmp::min(usize::from(buf[3]), self.max_read_len);
+                    message[4..][..n].copy_from_slice(&self.data[start..][..n]);
+                }
+                _ => panic!("unexpected message {buf:?}"),
+            }
+            self.response.extend(message);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn probe_data_chunk_len_detects_supported_len() {
+        let data: Vec<u8> = (1..=64).collect();
+        let mut dev = FakeDevice::new(data.clone(), 28);
+        assert_eq!(probe_data_chunk_len(&mut dev).unwrap(), Some(28));
+        let mut dev = FakeDevice::new(data, 26);
+        assert_eq!(probe_data_chunk_len(&mut dev).unwrap(), Some(26));
+    }
+
+    #[test]
+    fn probe_data_chunk_len_cannot_tell_zeros() {
+        let mut data: Vec<u8> = (1..=64).collect();
+        data[26..28].fill(0);
+        let mut dev = FakeDevice::new(data, 28);
+        assert_eq!(probe_data_chunk_len(&mut dev).unwrap(), None);
+    }
+
+    #[test]
+    fn maybe_switch_profile_restores_profile_on_signal() {
+        let mut dev = FakeDevice::new(vec![0; 64], 26);
+        dev.profile_index = 1;
+        let result = maybe_switch_profile(&mut dev, Some(2), |switch| {
+            assert_eq!(switch.dev.profile_index, 2);
+            switch.signals.interrupted.store(true, Ordering::Relaxed);
+            read_data(switch, 0, 64, 26)
+        });
+        assert_eq!(result.unwrap_err().to_string(), "interrupted by signal");
+        assert_eq!(dev.profile_index, 1);
+    }
+
+    #[test]
+    fn simulate_events_follow_options() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(["hhkb-studio-tools", "simulate"].iter().chain(args))
+        };
+        let cli = parse(&["-i", "p.toml", "--", "+Fn", "-Fn"]).unwrap();
+        let Command::Simulate(args) = cli.command else {
+            panic!("unexpected command");
+        };
+        assert_eq!(args.input.input, Some(PathBuf::from("p.toml")));
+        let fn_index = layout::position_index("Fn").unwrap();
+        assert_eq!(
+            args.events,
+            [KeyEvent::Press(fn_index), KeyEvent::Release(fn_index)]
+        );
+        assert!(parse(&["+Fn", "-Fn", "-i", "p.toml"]).is_err());
+    }
+}
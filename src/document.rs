@@ -0,0 +1,625 @@
+//! Format-preserving editor of profile files.
+
+use std::fmt::{self, Write as _};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::include;
+use crate::keymap::{self, LAYER_DATA_LEN};
+use crate::{layout, scancode};
+
+/// Profile TOML document which can be edited in place, keeping comments,
+/// ordering, and whitespace.
+#[derive(Clone, Debug)]
+pub struct ProfileDocument {
+    doc: toml_edit::DocumentMut,
+    /// Directory where the included files are resolved.
+    base_dir: PathBuf,
+}
+
+impl ProfileDocument {
+    /// Parses and validates profile document. The included files are
+    /// resolved relative to the `base_dir`.
+    pub fn parse(serialized: &str, base_dir: &Path) -> anyhow::Result<Self> {
+        include::parse_toml_string_at(serialized, base_dir)?;
+        let doc = serialized.parse()?;
+        Ok(ProfileDocument {
+            doc,
+            base_dir: base_dir.to_owned(),
+        })
+    }
+
+    /// Returns the number of layers.
+    fn num_layers(&self) -> usize {
+        match self.doc.get("layers") {
+            Some(toml_edit::Item::ArrayOfTables(tables)) => tables.len(),
+            Some(toml_edit::Item::Value(toml_edit::Value::Array(array))) => array.len(),
+            _ => 0,
+        }
+    }
+
+    /// Returns mutable table of the specified layer.
+    fn layer_table_mut(
+        &mut self,
+        layer_index: usize,
+    ) -> anyhow::Result<&mut dyn toml_edit::TableLike> {
+        let layers = self
+            .doc
+            .get_mut("layers")
+            .ok_or_else(|| anyhow::anyhow!("layers not found"))?;
+        let table: Option<&mut dyn toml_edit::TableLike> = if layers.is_array_of_tables() {
+            let tables = layers.as_array_of_tables_mut().unwrap();
+            tables
+                .get_mut(layer_index)
+                .map(|table| table as &mut dyn toml_edit::TableLike)
+        } else if let Some(array) = layers.as_array_mut() {
+            array
+                .get_mut(layer_index)
+                .and_then(|value| value.as_inline_table_mut())
+                .map(|table| table as &mut dyn toml_edit::TableLike)
+        } else {
+            None
+        };
+        table.ok_or_else(|| anyhow::anyhow!("layer #{layer_index} not found"))
+    }
+
+    /// Returns mutable scancodes array of the specified layer if the layer
+    /// isn't sparse.
+    fn layer_scancodes_mut(
+        &mut self,
+        layer_index: usize,
+    ) -> anyhow::Result<Option<&mut toml_edit::Array>> {
+        let table = self.layer_table_mut(layer_index)?;
+        let scancodes = table
+            .get_mut("scancodes")
+            .and_then(|item| item.as_array_mut());
+        Ok(scancodes)
+    }
+
+    /// Replaces the scancode at the `index` of the specified layer.
+    ///
+    /// The `keys` entry of the position (under any name or alias) is updated
+    /// if exists, since it overrides the `scancodes` array. Otherwise, the
+    /// array is updated, or a `keys` entry is added if the layer is sparse.
+    /// Fails if the edit wouldn't take effect.
+    pub fn set_scancode(
+        &mut self,
+        layer_index: usize,
+        index: usize,
+        code: u16,
+    ) -> anyhow::Result<()> {
+        assert!(index < LAYER_DATA_LEN / 2);
+        let old_doc = self.doc.clone();
+        self.set_scancode_unchecked(layer_index, index, code)?;
+        let data = self.to_data()?;
+        let range = layer_index * LAYER_DATA_LEN + index * 2..;
+        if data.get(range).is_none_or(|d| d[..2] != code.to_be_bytes()) {
+            self.doc = old_doc;
+            anyhow::bail!(
+                "layer #{layer_index}, {}: edit would be overridden",
+                layout::describe_position(index)
+            );
+        }
+        Ok(())
+    }
+
+    fn set_scancode_unchecked(
+        &mut self,
+        layer_index: usize,
+        index: usize,
+        code: u16,
+    ) -> anyhow::Result<()> {
+        let value: toml_edit::Value = format!("0x{code:04x}").parse().unwrap();
+        let table = self.layer_table_mut(layer_index)?;
+        if let Some(item) = table.get_mut("keys") {
+            let keys = item
+                .as_table_like_mut()
+                .ok_or_else(|| anyhow::anyhow!("unexpected type of keys"))?;
+            let names: Vec<String> = keys
+                .iter()
+                .filter(|(name, _)| layout::position_index(name) == Some(index))
+                .map(|(name, _)| name.to_owned())
+                .collect();
+            if !names.is_empty() {
+                for name in &names {
+                    // Keep label if the entry is written by label
+                    let label = scancode::scancode_to_label(code)
+                        .filter(|label| label.chars().all(|c| c.is_ascii_alphanumeric()))
+                        .filter(|&label| scancode::parse_scancode(label) == Some(code))
+                        .filter(|_| keys.get(name).is_some_and(|item| item.is_str()));
+                    let value = label.map_or_else(|| value.clone(), toml_edit::Value::from);
+                    set_value(keys, name, value);
+                }
+                return Ok(());
+            }
+        }
+        if let Some(scancodes) = self.layer_scancodes_mut(layer_index)? {
+            scancodes.replace(index, value);
+            refresh_annotation(scancodes, index);
+            return Ok(());
+        }
+        let table = self.layer_table_mut(layer_index)?;
+        let keys = table
+            .entry("keys")
+            .or_insert(toml_edit::Item::Table(toml_edit::Table::new()))
+            .as_table_like_mut()
+            .ok_or_else(|| anyhow::anyhow!("unexpected type of keys"))?;
+        set_value(keys, &layout::position_name(index), value);
+        Ok(())
+    }
+
+    /// Reformats scancodes arrays in the canonical layout.
+    ///
+    /// Comments are moved along with the keyboard rows. Annotation comments
+    /// are regenerated if the array had annotations.
+    pub fn format_scancodes(&mut self) -> anyhow::Result<()> {
+        for layer_index in 0..self.num_layers() {
+            let Some(scancodes) = self.layer_scancodes_mut(layer_index)? else {
+                continue;
+            };
+            let (rows, footer, annotate) = collect_row_comments(scancodes);
+            let codes = array_scancodes(scancodes, 0..scancodes.len());
+            let mut buffer = String::from("[\n");
+            for (row_codes, comments) in codes.chunks(15).zip(&rows) {
+                // Multiple comments of the row are moved above the row
+                let mut leading = comments.leading.clone();
+                let trailing = if let [comment] = comments.trailing.as_slice() {
+                    Some(comment.as_str())
+                } else {
+                    leading.extend(comments.trailing.iter().cloned());
+                    None
+                };
+                for comment in &leading {
+                    writeln!(buffer, "  {comment}").unwrap();
+                }
+                keymap::format_scancodes_row(&mut buffer, row_codes, trailing, annotate);
+            }
+            for comment in &footer {
+                writeln!(buffer, "  {comment}").unwrap();
+            }
+            buffer.push(']');
+            let mut array: toml_edit::Array = buffer
+                .parse::<toml_edit::Value>()?
+                .as_array()
+                .unwrap()
+                .clone();
+            // Keep the decoration around the array
+            *array.decor_mut() = scancodes.decor().clone();
+            *scancodes = array;
+        }
+        Ok(())
+    }
+
+    /// Sets string value in the `[metadata]` table.
+    pub fn set_metadata(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        if !self.doc.contains_key("metadata") {
+            self.insert_metadata_table();
+        }
+        let metadata = self.doc["metadata"]
+            .as_table_like_mut()
+            .ok_or_else(|| anyhow::anyhow!("unexpected type of metadata"))?;
+        set_value(metadata, key, value.into());
+        Ok(())
+    }
+
+    /// Inserts empty metadata table before the layers.
+    fn insert_metadata_table(&mut self) {
+        let mut table = toml_edit::Table::new();
+        table.set_position(0);
+        // Move the file header comment (separated by blank line) from the
+        // first layer to the metadata table.
+        let first_layer = self
+            .doc
+            .get_mut("layers")
+            .and_then(|item| item.as_array_of_tables_mut())
+            .and_then(|tables| tables.get_mut(0));
+        let mut header = first_layer
+            .map(|table| take_header_comment(table.decor_mut()))
+            .unwrap_or_default();
+        if header.is_empty() && self.doc.iter().any(|(_, item)| item.is_value()) {
+            // Separate from the top-level values such as format_version
+            header.push('\n');
+        }
+        table.decor_mut().set_prefix(header);
+        self.doc.insert("metadata", toml_edit::Item::Table(table));
+    }
+
+    /// Upgrades the document to the current format version. Returns the
+    /// original version, or `None` if the document is up to date.
+    pub fn migrate(&mut self) -> anyhow::Result<Option<i64>> {
+        let maybe_version = self.doc.get("format_version").map(|item| item.as_integer());
+        let version = match maybe_version {
+            Some(Some(version)) if version == keymap::FORMAT_VERSION => return Ok(None),
+            Some(Some(version)) => version,
+            Some(None) => anyhow::bail!("invalid format_version"),
+            None => 1,
+        };
+        keymap::upgrade_toml_document(&mut self.doc, version)?;
+        let value = toml_edit::Value::from(keymap::FORMAT_VERSION);
+        if self.doc.contains_key("format_version") {
+            set_value(self.doc.as_table_mut(), "format_version", value);
+        } else {
+            self.insert_format_version(value);
+        }
+        Ok(Some(version))
+    }
+
+    /// Inserts `format_version` at the top of the document.
+    fn insert_format_version(&mut self, value: toml_edit::Value) {
+        let mut key = toml_edit::Key::new("format_version");
+        // Keep the file header comment at the top
+        let first_value_key = self
+            .doc
+            .iter_mut()
+            .find(|(_, item)| item.is_value())
+            .map(|(key, _)| key);
+        if let Some(mut first_key) = first_value_key {
+            let decor = first_key.leaf_decor_mut();
+            let prefix = decor.prefix().cloned();
+            decor.set_prefix("");
+            if let Some(prefix) = prefix {
+                key.leaf_decor_mut().set_prefix(prefix);
+            }
+        } else if let Some(table) = self.first_table_mut() {
+            let header = take_header_comment(table.decor_mut());
+            key.leaf_decor_mut().set_prefix(header);
+        }
+        self.doc
+            .insert_formatted(&key, toml_edit::Item::Value(value));
+        self.doc.sort_values_by(|key1, _, key2, _| {
+            let is_version = |key: &toml_edit::Key| key.get() == "format_version";
+            is_version(key2).cmp(&is_version(key1))
+        });
+    }
+
+    /// Returns the first table in the document order.
+    fn first_table_mut(&mut self) -> Option<&mut toml_edit::Table> {
+        let first_key = self
+            .doc
+            .iter()
+            .filter_map(|(key, item)| {
+                let position = match item {
+                    toml_edit::Item::Table(table) => table.position(),
+                    toml_edit::Item::ArrayOfTables(tables) => tables.get(0)?.position(),
+                    _ => None,
+                };
+                Some((position?, key.to_owned()))
+            })
+            .min()?
+            .1;
+        match self.doc.get_mut(&first_key)? {
+            toml_edit::Item::Table(table) => Some(table),
+            toml_edit::Item::ArrayOfTables(tables) => tables.get_mut(0),
+            _ => None,
+        }
+    }
+
+    /// Returns profile data of all layers.
+    pub fn to_data(&self) -> anyhow::Result<Vec<u8>> {
+        include::parse_toml_string_at(&self.doc.to_string(), &self.base_dir)
+    }
+}
+
+/// Splits the file header comment (separated by blank line) from the table
+/// decoration. A blank line is left before the table.
+fn take_header_comment(decor: &mut toml_edit::Decor) -> String {
+    let prefix = decor
+        .prefix()
+        .and_then(|s| s.as_str())
+        .unwrap_or("")
+        .to_owned();
+    let pos = prefix.rfind("\n\n").map_or(0, |pos| pos + 2);
+    if prefix[pos..].starts_with('\n') {
+        decor.set_prefix(&prefix[pos..]);
+    } else {
+        decor.set_prefix(format!("\n{}", &prefix[pos..]));
+    }
+    prefix[..pos].to_owned()
+}
+
+/// Returns the ranges of the value indices per source line of the array.
+fn value_lines(array: &toml_edit::Array) -> Vec<Range<usize>> {
+    let starts: Vec<usize> = array
+        .iter()
+        .enumerate()
+        .filter(|&(j, value)| j == 0 || text_before_value(value).contains('\n'))
+        .map(|(j, _)| j)
+        .collect();
+    let ends = starts.iter().skip(1).copied().chain([array.len()]);
+    starts.iter().zip(ends).map(|(&a, b)| a..b).collect()
+}
+
+fn text_before_value(value: &toml_edit::Value) -> &str {
+    value
+        .decor()
+        .prefix()
+        .and_then(|s| s.as_str())
+        .unwrap_or("")
+}
+
+/// Returns the text between the values before `end` and the value at `end`
+/// (or the closing bracket.)
+fn text_before(array: &toml_edit::Array, end: usize) -> &str {
+    match array.get(end) {
+        Some(value) => text_before_value(value),
+        None => array.trailing().as_str().unwrap_or(""),
+    }
+}
+
+fn set_text_before(array: &mut toml_edit::Array, end: usize, text: String) {
+    match array.get_mut(end) {
+        Some(value) => value.decor_mut().set_prefix(text),
+        None => array.set_trailing(text),
+    }
+}
+
+/// Locates the annotation comment line (including the newline) just after
+/// the line of `num_codes` values in the `text` following the values.
+fn annotation_span(text: &str, num_codes: usize) -> Option<Range<usize>> {
+    let start = text.find('\n')? + 1;
+    let len = text[start..].find('\n')?;
+    keymap::is_annotation_line(&text[start..start + len], num_codes)
+        .then_some(start..start + len + 1)
+}
+
+fn array_scancodes(array: &toml_edit::Array, range: Range<usize>) -> Vec<u16> {
+    array
+        .iter()
+        .skip(range.start)
+        .take(range.len())
+        .map(|value| {
+            let code = value.as_integer().and_then(|n| u16::try_from(n).ok());
+            code.unwrap_or(keymap::TRANSPARENT_SCANCODE)
+        })
+        .collect()
+}
+
+/// Regenerates the annotation comment of the line containing the value at
+/// the `index`. The comment is added if the other lines are annotated.
+fn refresh_annotation(array: &mut toml_edit::Array, index: usize) {
+    let lines = value_lines(array);
+    let annotated = lines
+        .iter()
+        .any(|line| annotation_span(text_before(array, line.end), line.len()).is_some());
+    let Some(line) = lines.into_iter().find(|line| line.contains(&index)) else {
+        return;
+    };
+    let mut text = text_before(array, line.end).to_owned();
+    let annotation = keymap::annotation_line(&array_scancodes(array, line.clone()))
+        .map(|line| line + "\n")
+        .unwrap_or_default();
+    if let Some(span) = annotation_span(&text, line.len()) {
+        text.replace_range(span, &annotation);
+    } else if let Some(pos) = text.find('\n').filter(|_| annotated) {
+        text.insert_str(pos + 1, &annotation);
+    } else {
+        return;
+    }
+    set_text_before(array, line.end, text);
+}
+
+/// Comments around keyboard row in scancodes array.
+#[derive(Clone, Debug, Default)]
+struct RowComments {
+    /// Comment lines above the row.
+    leading: Vec<String>,
+    /// Comments at the end of the source lines of the row.
+    trailing: Vec<String>,
+}
+
+/// Collects the comments in the scancodes array per keyboard row, except for
+/// the annotations generated by the tool. Returns the comments of the rows,
+/// the comments after the last row, and whether the array had annotations.
+fn collect_row_comments(array: &toml_edit::Array) -> (Vec<RowComments>, Vec<String>, bool) {
+    let num_rows = array.len().div_ceil(15);
+    let mut rows = vec![RowComments::default(); num_rows];
+    let mut footer = Vec::new();
+    let mut annotated = false;
+    let comment_lines = |text: &str| -> Vec<String> {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with('#'))
+            .map(str::to_owned)
+            .collect()
+    };
+    // Comments after "[" and above the first row
+    if let Some(value) = array.get(0) {
+        rows[0]
+            .leading
+            .extend(comment_lines(text_before_value(value)));
+    }
+    for line in value_lines(array) {
+        for j in line.clone() {
+            let suffix = array.get(j).unwrap().decor().suffix();
+            let suffix = suffix.and_then(|s| s.as_str()).unwrap_or("");
+            rows[j / 15].trailing.extend(comment_lines(suffix));
+        }
+        let mut text = text_before(array, line.end).to_owned();
+        if let Some(span) = annotation_span(&text, line.len()) {
+            text.replace_range(span, "");
+            annotated = true;
+        }
+        let (head, rest) = text.split_once('\n').unwrap_or((&text, ""));
+        rows[(line.end - 1) / 15]
+            .trailing
+            .extend(comment_lines(head));
+        match rows.get_mut(line.end / 15) {
+            Some(row) if line.end < array.len() => row.leading.extend(comment_lines(rest)),
+            _ => footer.extend(comment_lines(rest)),
+        }
+    }
+    (rows, footer, annotated)
+}
+
+/// Inserts or replaces the value, keeping the decoration of the existing
+/// value.
+fn set_value(table: &mut dyn toml_edit::TableLike, key: &str, mut value: toml_edit::Value) {
+    if let Some(old_value) = table.get_mut(key).and_then(|item| item.as_value_mut()) {
+        *value.decor_mut() = old_value.decor().clone();
+        *old_value = value;
+    } else {
+        table.insert(key, toml_edit::Item::Value(value));
+    }
+}
+
+impl fmt::Display for ProfileDocument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.doc.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::Layer;
+
+    fn parse_document(serialized: &str) -> ProfileDocument {
+        ProfileDocument::parse(serialized, Path::new(".")).unwrap()
+    }
+
+    #[test]
+    fn set_scancode_refreshes_annotation() {
+        let mut scancodes = vec![0x0004; LAYER_DATA_LEN / 2];
+        scancodes[15..30].fill(keymap::TRANSPARENT_SCANCODE);
+        let data = keymap::layers_to_profile_data(&[scancodes.clone()]).unwrap();
+        let mut doc = parse_document(&keymap::serialize_to_annotated_toml_string(
+            &data,
+            Layer::Base,
+        ));
+        // Annotated row, and row without annotation
+        for (index, code) in [(1, 0x00a9), (16, 0x0050)] {
+            doc.set_scancode(0, index, code).unwrap();
+            scancodes[index] = code;
+        }
+        let data = keymap::layers_to_profile_data(&[scancodes]).unwrap();
+        assert_eq!(
+            doc.to_string(),
+            keymap::serialize_to_annotated_toml_string(&data, Layer::Base)
+        );
+    }
+
+    #[test]
+    fn set_scancode_keeps_unannotated_array() {
+        let data = keymap::layers_to_profile_data(&[vec![0x0004; LAYER_DATA_LEN / 2]]).unwrap();
+        let mut doc = parse_document(&keymap::serialize_to_toml_string(&data));
+        doc.set_scancode(0, 1, 0x00a9).unwrap();
+        let mut scancodes = keymap::layers_scancodes(&data).remove(0);
+        scancodes[1] = 0x00a9;
+        let data = keymap::layers_to_profile_data(&[scancodes]).unwrap();
+        assert_eq!(doc.to_string(), keymap::serialize_to_toml_string(&data));
+    }
+
+    /// Inserts the `line` above the `row` of the first scancodes array.
+    fn insert_line_above_row(serialized: &str, row: usize, line: &str) -> String {
+        let pos = serialized.match_indices("\n  0x").nth(row).unwrap().0 + 1;
+        let mut serialized = serialized.to_owned();
+        serialized.insert_str(pos, line);
+        serialized
+    }
+
+    #[test]
+    fn format_scancodes_keeps_comments() {
+        let codes: Vec<String> = (0..LAYER_DATA_LEN / 2)
+            .map(|j| format!("0x{:04x}", 0x0004 + j % 15))
+            .collect();
+        let serialized = format!(
+            "[[layers]]\nscancodes = [ # after bracket\n  {}, # esc and 1\n  {},\n  # tab row\n  {},\n  # footer\n]\n",
+            codes[..2].join(", "),
+            codes[2..15].join(", "),
+            codes[15..].join(", "),
+        );
+        let mut doc = parse_document(&serialized);
+        doc.format_scancodes().unwrap();
+        let row = codes[..15].join(", ");
+        let formatted = doc.to_string();
+        assert!(formatted.starts_with(&format!(
+            "[[layers]]\nscancodes = [\n  # after bracket\n  {row}, # esc and 1\n  # tab row\n  {row},\n"
+        )));
+        assert!(formatted.ends_with(&format!("  {row},\n  # footer\n]\n")));
+        assert_eq!(
+            doc.to_data().unwrap(),
+            include::parse_toml_string_at(&serialized, Path::new(".")).unwrap()
+        );
+        // Idempotent
+        doc.format_scancodes().unwrap();
+        assert_eq!(doc.to_string(), formatted);
+    }
+
+    #[test]
+    fn format_scancodes_regenerates_annotations() {
+        let data = keymap::layers_to_profile_data(&[vec![0x0004; LAYER_DATA_LEN / 2]]).unwrap();
+        let annotated = keymap::serialize_to_annotated_toml_string(&data, Layer::Base);
+        // Stale annotation of the first key, and hand-written comment
+        let serialized = annotated.replacen("0x0004", "0x00a9", 1);
+        let mut doc = parse_document(&insert_line_above_row(&serialized, 1, "  # tab row\n"));
+        doc.format_scancodes().unwrap();
+        let mut scancodes = keymap::layers_scancodes(&data).remove(0);
+        scancodes[0] = 0x00a9;
+        let data = keymap::layers_to_profile_data(&[scancodes]).unwrap();
+        let expected = keymap::serialize_to_annotated_toml_string(&data, Layer::Base);
+        assert_eq!(
+            doc.to_string(),
+            insert_line_above_row(&expected, 1, "  # tab row\n")
+        );
+    }
+
+    #[test]
+    fn set_scancode_updates_overriding_keys() {
+        let data = keymap::layers_to_profile_data(&[vec![0x0004; LAYER_DATA_LEN / 2]]).unwrap();
+        let serialized = keymap::serialize_to_toml_string(&data)
+            + "[layers.keys]\nsemicolon = \"Left\"\nCapsLock = 0x0039\n";
+        let mut doc = parse_document(&serialized);
+        let semicolon = layout::position_index("Semicolon").unwrap();
+        let control = layout::position_index("Control").unwrap();
+        doc.set_scancode(0, semicolon, 0x0052).unwrap();
+        doc.set_scancode(0, control, 0x00e0).unwrap();
+        doc.set_scancode(0, 0, 0x0029).unwrap();
+        let formatted = doc.to_string();
+        assert!(formatted.ends_with("[layers.keys]\nsemicolon = \"Up\"\nCapsLock = 0x00e0\n"));
+        let scancodes = keymap::layers_scancodes(&doc.to_data().unwrap()).remove(0);
+        assert_eq!(
+            [scancodes[0], scancodes[semicolon], scancodes[control]],
+            [0x0029, 0x0052, 0x00e0]
+        );
+    }
+
+    #[test]
+    fn set_scancode_adds_keys_to_sparse_layer() {
+        let mut doc = parse_document("[[layers]]\ninherits = \"transparent\"\n");
+        doc.set_scancode(0, 1, 0x003a).unwrap();
+        assert_eq!(
+            doc.to_string(),
+            "[[layers]]\ninherits = \"transparent\"\n\n[layers.keys]\n1 = 0x003a\n"
+        );
+    }
+
+    #[test]
+    fn set_metadata_keeps_header_comment_at_top() {
+        let layer = format!(
+            "[[layers]]\nscancodes = [{}]\n",
+            "0x0000, ".repeat(LAYER_DATA_LEN / 2)
+        );
+        let mut doc = parse_document(&format!(
+            "format_version = 1\n\n# header\n\n# Base\n{layer}"
+        ));
+        doc.set_metadata("name", "plain").unwrap();
+        doc.set_metadata("name", "fancy").unwrap();
+        assert_eq!(
+            doc.to_string(),
+            format!(
+                "format_version = 1\n\n# header\n\n[metadata]\nname = \"fancy\"\n\n# Base\n{layer}"
+            )
+        );
+    }
+
+    #[test]
+    fn migrate_inserts_format_version() {
+        let mut doc = parse_document("# header\n\n[[layers]]\ninherits = \"transparent\"\n");
+        assert_eq!(doc.migrate().unwrap(), Some(1));
+        assert_eq!(
+            doc.to_string(),
+            "# header\n\nformat_version = 1\n\n[[layers]]\ninherits = \"transparent\"\n"
+        );
+        assert_eq!(doc.migrate().unwrap(), None);
+    }
+}
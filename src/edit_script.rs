@@ -0,0 +1,263 @@
+//! Line-based edit scripts for profiles.
+//!
+//! Each line is one of the following commands. Empty lines and `#` comments
+//! are ignored. `<layers>` is a layer name or `all`.
+//!
+//! ```text
+//! set <layer> <position> <scancode>
+//! swap <layers> <position> <position>
+//! copy <layer> <layer> <position>:<position> [<position>]
+//! clear <layer>
+//! replace <layers> <scancode> <scancode>
+//! ```
+
+use anyhow::Context as _;
+
+use crate::keymap::Layer;
+use crate::profile::{Profile, Region};
+use crate::{layout, scancode};
+
+/// Edit command parsed from script line.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Edit {
+    Set {
+        layer: Layer,
+        position: usize,
+        scancode: u16,
+    },
+    Swap {
+        /// Layers to edit, or all layers if empty.
+        layers: Vec<Layer>,
+        a: usize,
+        b: usize,
+    },
+    Copy {
+        from: Layer,
+        to: Layer,
+        region: Region,
+        at: Option<usize>,
+    },
+    Clear {
+        layer: Layer,
+    },
+    Replace {
+        /// Layers to edit, or all layers if empty.
+        layers: Vec<Layer>,
+        from: u16,
+        to: u16,
+    },
+}
+
+/// Edit command with the source line number (1-based.)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScriptLine {
+    pub line: usize,
+    pub edit: Edit,
+}
+
+/// Parses edit script. Errors of all lines are reported together.
+pub fn parse_edit_script(script: &str) -> anyhow::Result<Vec<ScriptLine>> {
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+    for (i, text) in script.lines().enumerate() {
+        let text = text.split_once('#').map_or(text, |(s, _)| s);
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        match parse_edit(&words) {
+            Ok(edit) => lines.push(ScriptLine { line: i + 1, edit }),
+            Err(err) => errors.push(format!("line {}: {err:#}", i + 1)),
+        }
+    }
+    anyhow::ensure!(errors.is_empty(), "{}", errors.join("\n"));
+    Ok(lines)
+}
+
+fn parse_edit(words: &[&str]) -> anyhow::Result<Edit> {
+    let edit = match *words {
+        ["set", layer, position, code] => Edit::Set {
+            layer: parse_layer(layer)?,
+            position: parse_position(position)?,
+            scancode: parse_scancode(code)?,
+        },
+        ["swap", layers, a, b] => Edit::Swap {
+            layers: parse_layers(layers)?,
+            a: parse_position(a)?,
+            b: parse_position(b)?,
+        },
+        ["copy", from, to, region] => Edit::Copy {
+            from: parse_layer(from)?,
+            to: parse_layer(to)?,
+            region: region.parse()?,
+            at: None,
+        },
+        ["copy", from, to, region, at] => Edit::Copy {
+            from: parse_layer(from)?,
+            to: parse_layer(to)?,
+            region: region.parse()?,
+            at: Some(parse_position(at)?),
+        },
+        ["clear", layer] => Edit::Clear {
+            layer: parse_layer(layer)?,
+        },
+        ["replace", layers, from, to] => Edit::Replace {
+            layers: parse_layers(layers)?,
+            from: parse_scancode(from)?,
+            to: parse_scancode(to)?,
+        },
+        [command @ ("set" | "swap" | "copy" | "clear" | "replace"), ..] => {
+            anyhow::bail!("wrong number of arguments to {command}")
+        }
+        [command, ..] => anyhow::bail!("unknown command: {command}"),
+        [] => unreachable!(),
+    };
+    Ok(edit)
+}
+
+fn parse_layer(name: &str) -> anyhow::Result<Layer> {
+    Layer::from_name(&name.to_ascii_lowercase())
+        .ok_or_else(|| anyhow::anyhow!("unknown layer: {name}"))
+}
+
+/// Parses layer name or "all" (empty list.)
+fn parse_layers(name: &str) -> anyhow::Result<Vec<Layer>> {
+    if name.eq_ignore_ascii_case("all") {
+        Ok(vec![])
+    } else {
+        Ok(vec![parse_layer(name)?])
+    }
+}
+
+fn parse_position(name: &str) -> anyhow::Result<usize> {
+    layout::position_index(name).ok_or_else(|| anyhow::anyhow!("unknown key position: {name}"))
+}
+
+fn parse_scancode(s: &str) -> anyhow::Result<u16> {
+    scancode::parse_scancode(s).ok_or_else(|| anyhow::anyhow!("unknown scancode: {s}"))
+}
+
+/// Applies the edits in order. The profile is left unchanged if any edit
+/// fails.
+pub fn apply_edits(profile: &mut Profile, lines: &[ScriptLine]) -> anyhow::Result<()> {
+    let mut edited = profile.clone();
+    for ScriptLine { line, edit } in lines {
+        apply_edit(&mut edited, edit).with_context(|| format!("line {line}"))?;
+    }
+    *profile = edited;
+    Ok(())
+}
+
+fn apply_edit(profile: &mut Profile, edit: &Edit) -> anyhow::Result<()> {
+    match edit {
+        Edit::Set {
+            layer,
+            position,
+            scancode,
+        } => profile.set_scancode(*layer, *position, *scancode),
+        Edit::Swap { layers, a, b } => profile.swap_keys(layers, *a, *b),
+        Edit::Copy {
+            from,
+            to,
+            region,
+            at,
+        } => profile.copy_region(*from, *to, region, *at),
+        Edit::Clear { layer } => profile.clear_layer(*layer),
+        Edit::Replace { layers, from, to } => {
+            profile.replace_scancode(layers, *from, *to)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_script_lines() {
+        let script = "\
+# swap modifiers
+set fn1 CapsLock LControl
+swap all LAlt LMeta  # both layers
+
+copy Fn1 fn2 r0c1:r0c3 r1c1
+clear fn3
+replace base 0x0039 LControl
+";
+        let lines = parse_edit_script(script).unwrap();
+        let edits: Vec<(usize, Edit)> = lines.into_iter().map(|l| (l.line, l.edit)).collect();
+        assert_eq!(
+            edits,
+            [
+                (
+                    2,
+                    Edit::Set {
+                        layer: Layer::Fn1,
+                        position: 30,
+                        scancode: 0x00e0,
+                    }
+                ),
+                (
+                    3,
+                    Edit::Swap {
+                        layers: vec![],
+                        a: 62,
+                        b: 63,
+                    }
+                ),
+                (
+                    5,
+                    Edit::Copy {
+                        from: Layer::Fn1,
+                        to: Layer::Fn2,
+                        region: Region {
+                            rows: 0..1,
+                            columns: 1..4,
+                        },
+                        at: Some(16),
+                    }
+                ),
+                (6, Edit::Clear { layer: Layer::Fn3 }),
+                (
+                    7,
+                    Edit::Replace {
+                        layers: vec![Layer::Base],
+                        from: 0x0039,
+                        to: 0x00e0,
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_script_reports_all_errors() {
+        let script = "set fn1 Q\nswap fn4 A B\nclear base\nfrobnicate\nset fn1 Nowhere A\n";
+        let err = parse_edit_script(script).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: wrong number of arguments to set\n\
+             line 2: unknown layer: fn4\n\
+             line 4: unknown command: frobnicate\n\
+             line 5: unknown key position: Nowhere"
+        );
+    }
+
+    #[test]
+    fn apply_edits_is_atomic() {
+        let data = vec![0; crate::keymap::LAYER_DATA_LEN * 2];
+        let mut profile = Profile::from_data(&data).unwrap();
+        let lines = parse_edit_script("set base A 0x0004\nclear fn2\n").unwrap();
+        let err = apply_edits(&mut profile, &lines).unwrap_err();
+        assert_eq!(format!("{err:#}"), "line 2: no Fn2 layer in profile");
+        assert_eq!(profile.to_data(), data);
+
+        let lines = parse_edit_script("set base A 0x0004\nswap all A B\n").unwrap();
+        apply_edits(&mut profile, &lines).unwrap();
+        let a = layout::position_index("A").unwrap();
+        let b = layout::position_index("B").unwrap();
+        assert_eq!(profile.scancodes(Layer::Base).unwrap()[b], 0x0004);
+        assert_eq!(profile.scancodes(Layer::Base).unwrap()[a], 0x0000);
+    }
+}
@@ -0,0 +1,273 @@
+//! Graph of layer activations.
+
+use std::fmt::{self, Write as _};
+
+use crate::keymap::{self, Layer};
+use crate::layout;
+
+/// Keys on the `from` layer activating the `to` layer.
+#[derive(Clone, Debug)]
+pub struct Edge {
+    pub from: Layer,
+    pub to: Layer,
+    /// Indices of the key positions.
+    pub positions: Vec<usize>,
+    /// Whether the edge is a part of cycle.
+    pub in_cycle: bool,
+}
+
+/// Which layers can activate which layers.
+#[derive(Clone, Debug)]
+pub struct LayerGraph {
+    pub layers: Vec<Layer>,
+    /// Edges between distinct layers.
+    pub edges: Vec<Edge>,
+    /// Whether the layer can be activated from the base layer.
+    pub reachable: Vec<bool>,
+    /// Whether the layer keeps any of the activating keys, which deactivates
+    /// the layer when released.
+    pub returnable: Vec<bool>,
+}
+
+impl LayerGraph {
+    pub fn new(layers_scancodes: &[Vec<u16>]) -> Self {
+        let layers: Vec<Layer> = (0..layers_scancodes.len())
+            .map_while(Layer::from_index)
+            .collect();
+        let activations = keymap::layer_activations(layers_scancodes);
+        let mut edges: Vec<Edge> = Vec::new();
+        for &(from, j, to) in &activations {
+            // The key activating the layer itself is the one held
+            if from == to {
+                continue;
+            }
+            if let Some(edge) = edges.iter_mut().find(|e| e.from == from && e.to == to) {
+                edge.positions.push(j);
+            } else {
+                edges.push(Edge {
+                    from,
+                    to,
+                    positions: vec![j],
+                    in_cycle: false,
+                });
+            }
+        }
+
+        // Transitive closure of the edges
+        let n = layers.len();
+        let mut connected = vec![vec![false; n]; n];
+        for edge in &edges {
+            connected[edge.from.index()][edge.to.index()] = true;
+        }
+        for k in 0..n {
+            for i in 0..n {
+                for j in 0..n {
+                    connected[i][j] |= connected[i][k] && connected[k][j];
+                }
+            }
+        }
+        for edge in &mut edges {
+            edge.in_cycle = connected[edge.to.index()][edge.from.index()];
+        }
+
+        // If the activating key is remapped on the activated layer, the
+        // layer may not be deactivated when the key is released.
+        let returnable = layers
+            .iter()
+            .map(|&layer| {
+                let mut incoming = activations
+                    .iter()
+                    .filter(|&&(from, _, to)| from != layer && to == layer)
+                    .peekable();
+                incoming.peek().is_none()
+                    || incoming.any(|&(_, j, _)| activations.contains(&(layer, j, layer)))
+            })
+            .collect();
+
+        LayerGraph {
+            layers,
+            edges,
+            reachable: keymap::reachable_layers(layers_scancodes),
+            returnable,
+        }
+    }
+
+    /// Returns the notes about the layer, e.g. "unreachable".
+    fn layer_notes(&self, layer: Layer) -> Vec<&'static str> {
+        let mut notes = Vec::new();
+        if !self.reachable[layer.index()] {
+            notes.push("unreachable");
+        }
+        if !self.returnable[layer.index()] {
+            notes.push("no way back to Base");
+        }
+        if self.edges.iter().any(|e| e.in_cycle && e.from == layer) {
+            notes.push("in cycle");
+        }
+        notes
+    }
+
+    /// Formats the graph in Graphviz DOT language.
+    pub fn to_dot(&self) -> String {
+        let mut buffer = String::from("digraph layers {\n");
+        for &layer in &self.layers {
+            let notes = self.layer_notes(layer);
+            let mut attrs = vec![format!("label=\"{}\"", format_label(layer, &notes))];
+            if !self.reachable[layer.index()] {
+                attrs.push("style=dashed".to_owned());
+            }
+            if !self.returnable[layer.index()] {
+                attrs.push("color=red".to_owned());
+            }
+            writeln!(buffer, "  {} [{}];", layer.name(), attrs.join(", ")).unwrap();
+        }
+        for edge in &self.edges {
+            let mut attrs = vec![format!("label=\"{}\"", format_positions(&edge.positions))];
+            if edge.in_cycle {
+                attrs.push("color=red".to_owned());
+            }
+            writeln!(
+                buffer,
+                "  {} -> {} [{}];",
+                edge.from.name(),
+                edge.to.name(),
+                attrs.join(", ")
+            )
+            .unwrap();
+        }
+        buffer.push_str("}\n");
+        buffer
+    }
+}
+
+/// Formats the graph as adjacency list.
+impl fmt::Display for LayerGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for &layer in &self.layers {
+            writeln!(f, "{}", format_label(layer, &self.layer_notes(layer)))?;
+            for edge in self.edges.iter().filter(|e| e.from == layer) {
+                let positions = format_positions(&edge.positions);
+                let cycle = if edge.in_cycle { " (cycle)" } else { "" };
+                writeln!(f, "  -> {} via {positions}{cycle}", edge.to)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn format_label(layer: Layer, notes: &[&str]) -> String {
+    if notes.is_empty() {
+        layer.to_string()
+    } else {
+        format!("{layer} ({})", notes.join(", "))
+    }
+}
+
+fn format_positions(positions: &[usize]) -> String {
+    let names: Vec<_> = positions
+        .iter()
+        .map(|&j| layout::position_name(j))
+        .collect();
+    names.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::{LAYER_DATA_LEN, TRANSPARENT_SCANCODE};
+
+    /// Builds four transparent layers with the `keys` mapped.
+    fn layers_with(keys: &[(Layer, &str, u16)]) -> Vec<Vec<u16>> {
+        let mut layers_scancodes = vec![vec![TRANSPARENT_SCANCODE; LAYER_DATA_LEN / 2]; 4];
+        for &(layer, position, code) in keys {
+            let index = layout::position_index(position).unwrap();
+            layers_scancodes[layer.index()][index] = code;
+        }
+        layers_scancodes
+    }
+
+    #[test]
+    fn cycle_of_nested_layers() {
+        let graph = LayerGraph::new(&layers_with(&[
+            (Layer::Base, "Fn", 0x5101),
+            (Layer::Fn1, "A", 0x5102),
+            (Layer::Fn2, "A", 0x5102),
+            (Layer::Fn2, "S", 0x5101),
+        ]));
+        let cycle_edges: Vec<_> = graph
+            .edges
+            .iter()
+            .map(|e| (e.from, e.to, e.in_cycle))
+            .collect();
+        assert_eq!(
+            cycle_edges,
+            [
+                (Layer::Base, Layer::Fn1, false),
+                (Layer::Fn1, Layer::Fn2, true),
+                (Layer::Fn2, Layer::Fn1, true),
+                (Layer::Fn3, Layer::Fn1, false),
+            ]
+        );
+        assert_eq!(
+            graph.to_string(),
+            "\
+Base
+  -> Fn1 via Fn
+Fn1 (in cycle)
+  -> Fn2 via A (cycle)
+Fn2 (in cycle)
+  -> Fn1 via S, Fn (cycle)
+Fn3 (unreachable)
+  -> Fn1 via Fn
+"
+        );
+        assert_eq!(
+            graph.to_dot(),
+            r#"digraph layers {
+  base [label="Base"];
+  fn1 [label="Fn1 (in cycle)"];
+  fn2 [label="Fn2 (in cycle)"];
+  fn3 [label="Fn3 (unreachable)", style=dashed];
+  base -> fn1 [label="Fn"];
+  fn1 -> fn2 [label="A", color=red];
+  fn2 -> fn1 [label="S, Fn", color=red];
+  fn3 -> fn1 [label="Fn"];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn layer_reachable_only_from_unreachable_layer() {
+        let graph = LayerGraph::new(&layers_with(&[
+            (Layer::Base, "Fn", 0x5101),
+            (Layer::Fn2, "A", 0x5103),
+        ]));
+        assert_eq!(graph.reachable, [true, true, false, false]);
+        // A activating Fn3 is transparent on Fn3
+        assert_eq!(graph.returnable, [true, true, true, false]);
+    }
+
+    #[test]
+    fn layer_remapping_its_fn_key() {
+        let graph = LayerGraph::new(&layers_with(&[
+            (Layer::Base, "Fn", 0x5101),
+            (Layer::Fn1, "Fn", 0x0004),
+        ]));
+        assert_eq!(graph.reachable, [true, true, false, false]);
+        assert_eq!(graph.returnable, [true, false, true, true]);
+        assert_eq!(
+            graph.to_dot(),
+            r#"digraph layers {
+  base [label="Base"];
+  fn1 [label="Fn1 (no way back to Base)", color=red];
+  fn2 [label="Fn2 (unreachable)", style=dashed];
+  fn3 [label="Fn3 (unreachable)", style=dashed];
+  base -> fn1 [label="Fn"];
+  fn2 -> fn1 [label="Fn"];
+  fn3 -> fn1 [label="Fn"];
+}
+"#
+        );
+    }
+}
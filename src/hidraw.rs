@@ -0,0 +1,395 @@
+//! Utility to find and communicate with Linux hidraw devices.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// USB vendor ID of PFU Limited.
+pub const HHKB_VENDOR_ID: u16 = 0x04fe;
+/// USB product ID of HHKB Studio.
+pub const HHKB_STUDIO_PRODUCT_ID: u16 = 0x0016;
+
+/// Length of request/response messages.
+pub const MESSAGE_LEN: usize = 32;
+
+const SYSFS_HIDRAW_DIR: &str = "/sys/class/hidraw";
+
+// ioctl requests defined in <linux/hidraw.h>
+const HID_MAX_DESCRIPTOR_SIZE: usize = 4096;
+const HIDIOCGRDESCSIZE: libc::c_ulong = ioc_read(0x01, 4);
+const HIDIOCGRDESC: libc::c_ulong = ioc_read(0x02, 4 + HID_MAX_DESCRIPTOR_SIZE);
+const HIDIOCGRAWINFO: libc::c_ulong = ioc_read(0x03, 8);
+const fn hidiocgrawname(len: usize) -> libc::c_ulong {
+    ioc_read(0x04, len)
+}
+
+/// Encodes `_IOC(_IOC_READ, 'H', nr, size)` (asm-generic layout.)
+const fn ioc_read(nr: u8, size: usize) -> libc::c_ulong {
+    (2 << 30)
+        | ((size as libc::c_ulong) << 16)
+        | ((b'H' as libc::c_ulong) << 8)
+        | nr as libc::c_ulong
+}
+
+/// HID device properties found in sysfs.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    /// Path to the device node.
+    pub path: PathBuf,
+    pub bus_type: u16,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub name: String,
+}
+
+impl DeviceInfo {
+    pub fn is_hhkb_studio(&self) -> bool {
+        self.vendor_id == HHKB_VENDOR_ID && self.product_id == HHKB_STUDIO_PRODUCT_ID
+    }
+
+    pub fn bus_name(&self) -> &'static str {
+        match self.bus_type {
+            0x03 => "USB",
+            0x05 => "Bluetooth",
+            _ => "unknown bus",
+        }
+    }
+}
+
+/// Lists hidraw devices sorted by name.
+pub fn enumerate_devices() -> io::Result<Vec<DeviceInfo>> {
+    let mut devices = Vec::new();
+    let entries = match fs::read_dir(SYSFS_HIDRAW_DIR) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(devices),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let uevent_path = entry.path().join("device/uevent");
+        let uevent = match fs::read_to_string(&uevent_path) {
+            Ok(data) => data,
+            Err(err) => {
+                tracing::debug!(?uevent_path, %err, "failed to read uevent");
+                continue;
+            }
+        };
+        let path = Path::new("/dev").join(entry.file_name());
+        if let Some(info) = parse_uevent(path, &uevent) {
+            devices.push(info);
+        }
+    }
+    devices.sort_by_key(|info| {
+        // Sort numerically: hidraw2 < hidraw10
+        let name = info.path.file_name().unwrap().to_string_lossy();
+        let num: Option<u32> = name.trim_start_matches("hidraw").parse().ok();
+        (num, name.into_owned())
+    });
+    Ok(devices)
+}
+
+fn parse_uevent(path: PathBuf, uevent: &str) -> Option<DeviceInfo> {
+    let mut ids = None;
+    let mut name = String::new();
+    for line in uevent.lines() {
+        if let Some(value) = line.strip_prefix("HID_ID=") {
+            // e.g. "0003:000004FE:00000016"
+            let mut parts = value.split(':').map(|s| u32::from_str_radix(s, 16).ok());
+            let (Some(Some(bus)), Some(Some(vendor)), Some(Some(product))) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                continue;
+            };
+            ids = Some((bus as u16, vendor as u16, product as u16));
+        } else if let Some(value) = line.strip_prefix("HID_NAME=") {
+            name = value.to_owned();
+        }
+    }
+    let (bus_type, vendor_id, product_id) = ids?;
+    Some(DeviceInfo {
+        path,
+        bus_type,
+        vendor_id,
+        product_id,
+        name,
+    })
+}
+
+/// Device IDs reported by `HIDIOCGRAWINFO`.
+#[derive(Clone, Copy, Debug)]
+pub struct RawInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+/// `struct hidraw_devinfo`
+#[repr(C)]
+struct HidrawDevinfo {
+    bustype: u32,
+    vendor: i16,
+    product: i16,
+}
+
+/// `struct hidraw_report_descriptor`
+#[repr(C)]
+struct HidrawReportDescriptor {
+    size: u32,
+    value: [u8; HID_MAX_DESCRIPTOR_SIZE],
+}
+
+pub fn get_raw_info(file: &File) -> io::Result<RawInfo> {
+    let mut info = HidrawDevinfo {
+        bustype: 0,
+        vendor: 0,
+        product: 0,
+    };
+    // SAFETY: info is a valid hidraw_devinfo.
+    check_ioctl(unsafe { libc::ioctl(file.as_raw_fd(), HIDIOCGRAWINFO, &mut info) })?;
+    Ok(RawInfo {
+        vendor_id: info.vendor as u16,
+        product_id: info.product as u16,
+    })
+}
+
+pub fn get_raw_name(file: &File) -> io::Result<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: buf is valid for the specified length.
+    let n = check_ioctl(unsafe {
+        libc::ioctl(
+            file.as_raw_fd(),
+            hidiocgrawname(buf.len()),
+            buf.as_mut_ptr(),
+        )
+    })?;
+    let name = &buf[..usize::try_from(n).unwrap().min(buf.len())];
+    let name = name.split(|&c| c == b'\0').next().unwrap();
+    Ok(String::from_utf8_lossy(name).into_owned())
+}
+
+pub fn get_report_descriptor(file: &File) -> io::Result<Vec<u8>> {
+    let mut size: libc::c_int = 0;
+    // SAFETY: size is a valid c_int.
+    check_ioctl(unsafe { libc::ioctl(file.as_raw_fd(), HIDIOCGRDESCSIZE, &mut size) })?;
+    let mut desc = Box::new(HidrawReportDescriptor {
+        size: size.try_into().unwrap(),
+        value: [0; HID_MAX_DESCRIPTOR_SIZE],
+    });
+    // SAFETY: desc is a valid hidraw_report_descriptor.
+    check_ioctl(unsafe { libc::ioctl(file.as_raw_fd(), HIDIOCGRDESC, &mut *desc) })?;
+    let len = usize::try_from(desc.size)
+        .unwrap()
+        .min(HID_MAX_DESCRIPTOR_SIZE);
+    Ok(desc.value[..len].to_vec())
+}
+
+fn check_ioctl(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Checks if the opened device is the HHKB Studio interface which accepts
+/// our messages.
+pub fn verify_interface(file: &File) -> anyhow::Result<()> {
+    let info = get_raw_info(file)?;
+    let name = get_raw_name(file)?;
+    anyhow::ensure!(
+        info.vendor_id == HHKB_VENDOR_ID && info.product_id == HHKB_STUDIO_PRODUCT_ID,
+        "not a HHKB Studio: {name} ({:04x}:{:04x})",
+        info.vendor_id,
+        info.product_id
+    );
+    let desc = get_report_descriptor(file)?;
+    anyhow::ensure!(
+        has_vendor_reports(&desc, MESSAGE_LEN),
+        "not the vendor-defined interface of {name} (no {MESSAGE_LEN}-byte reports in \
+         vendor usage page)"
+    );
+    Ok(())
+}
+
+/// Checks if the report descriptor declares both input and output reports of
+/// `len` bytes in vendor-defined usage page.
+fn has_vendor_reports(desc: &[u8], len: usize) -> bool {
+    let mut usage_page = 0;
+    let mut report_size = 0;
+    let mut report_count = 0;
+    let mut has_input = false;
+    let mut has_output = false;
+    let mut pos = 0;
+    while pos < desc.len() {
+        let prefix = desc[pos];
+        if prefix == 0xfe {
+            // Long item: bDataSize follows
+            let data_len = desc.get(pos + 1).copied().unwrap_or(0);
+            pos += 3 + usize::from(data_len);
+            continue;
+        }
+        let data_len = match prefix & 0x03 {
+            3 => 4,
+            n => usize::from(n),
+        };
+        let Some(data) = desc.get(pos + 1..pos + 1 + data_len) else {
+            break;
+        };
+        let value = data
+            .iter()
+            .rev()
+            .fold(0u32, |acc, &b| (acc << 8) | u32::from(b));
+        match prefix & 0xfc {
+            0x04 => usage_page = value,   // Usage Page (global)
+            0x74 => report_size = value,  // Report Size (global)
+            0x94 => report_count = value, // Report Count (global)
+            0x80 | 0x90 if usage_page >= 0xff00 => {
+                // Input/Output (main)
+                let bits = report_size
+                    .checked_mul(report_count)
+                    .and_then(|bits| usize::try_from(bits).ok());
+                if bits == Some(len * 8) {
+                    has_input |= prefix & 0xfc == 0x80;
+                    has_output |= prefix & 0xfc == 0x90;
+                }
+            }
+            _ => {}
+        }
+        pos += 1 + data_len;
+    }
+    has_input && has_output
+}
+
+/// Device wrapper that fails if no response arrives within the `timeout`.
+#[derive(Debug)]
+pub struct TimeoutDevice<'a> {
+    file: &'a File,
+    timeout: Duration,
+}
+
+impl<'a> TimeoutDevice<'a> {
+    pub fn new(file: &'a File, timeout: Duration) -> Self {
+        TimeoutDevice { file, timeout }
+    }
+}
+
+impl Read for TimeoutDevice<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut fds = [libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let timeout_ms = self
+            .timeout
+            .as_millis()
+            .try_into()
+            .unwrap_or(libc::c_int::MAX);
+        // SAFETY: fds points to a valid array of one pollfd.
+        let n = unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout_ms) };
+        match n {
+            0 => Err(io::Error::new(io::ErrorKind::TimedOut, "no response")),
+            1.. => self.file.read(buf),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+impl Write for TimeoutDevice<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds report descriptor of a vendor collection with input and output
+    /// reports of `count` bytes.
+    fn report_descriptor(usage_page: [u8; 2], input_count: u8, output_count: u8) -> Vec<u8> {
+        #[rustfmt::skip]
+        let desc = vec![
+            0x06, usage_page[0], usage_page[1], // Usage Page
+            0x09, 0x61,                         // Usage
+            0xa1, 0x01,                         // Collection (Application)
+            0x09, 0x62,                         //   Usage
+            0x15, 0x00,                         //   Logical Minimum (0)
+            0x26, 0xff, 0x00,                   //   Logical Maximum (255)
+            0x75, 0x08,                         //   Report Size (8)
+            0x95, input_count,                  //   Report Count
+            0x81, 0x02,                         //   Input (Data, Var, Abs)
+            0x09, 0x63,                         //   Usage
+            0x95, output_count,                 //   Report Count
+            0x91, 0x02,                         //   Output (Data, Var, Abs)
+            0xc0,                               // End Collection
+        ];
+        desc
+    }
+
+    #[test]
+    fn vendor_reports_of_expected_len() {
+        let desc = report_descriptor([0x60, 0xff], 32, 32);
+        assert!(has_vendor_reports(&desc, 32));
+        assert!(!has_vendor_reports(&desc, 64));
+    }
+
+    #[test]
+    fn vendor_reports_require_input_and_output() {
+        assert!(!has_vendor_reports(
+            &report_descriptor([0x60, 0xff], 32, 1),
+            32
+        ));
+        assert!(!has_vendor_reports(
+            &report_descriptor([0x60, 0xff], 1, 32),
+            32
+        ));
+    }
+
+    #[test]
+    fn vendor_reports_ignore_standard_usage_page() {
+        let desc = report_descriptor([0x01, 0x00], 32, 32);
+        assert!(!has_vendor_reports(&desc, 32));
+    }
+
+    #[test]
+    fn vendor_reports_of_truncated_descriptor() {
+        let desc = report_descriptor([0x60, 0xff], 32, 32);
+        assert!(!has_vendor_reports(&desc[..desc.len() - 4], 32));
+        // Long item is skipped
+        let mut desc = vec![0xfe, 0x02, 0x00, 0xaa, 0xbb];
+        desc.extend(report_descriptor([0x60, 0xff], 32, 32));
+        assert!(has_vendor_reports(&desc, 32));
+    }
+
+    #[test]
+    fn vendor_reports_of_overflowing_size() {
+        #[rustfmt::skip]
+        let desc = [
+            0x06, 0x60, 0xff,             // Usage Page
+            0x77, 0x00, 0x00, 0x01, 0x00, // Report Size (65536)
+            0x97, 0x00, 0x00, 0x01, 0x00, // Report Count (65536)
+            0x81, 0x02,                   // Input (Data, Var, Abs)
+            0x91, 0x02,                   // Output (Data, Var, Abs)
+        ];
+        assert!(!has_vendor_reports(&desc, 32));
+    }
+
+    #[test]
+    fn parse_uevent_ids_and_name() {
+        let uevent = "DRIVER=hid-generic\nHID_ID=0003:000004FE:00000016\n\
+                      HID_NAME=PFU Limited HHKB-Studio\nHID_PHYS=usb-0000:00:14.0-1/input1\n";
+        let info = parse_uevent(PathBuf::from("/dev/hidraw1"), uevent).unwrap();
+        assert_eq!(
+            (info.bus_type, info.vendor_id, info.product_id),
+            (0x0003, 0x04fe, 0x0016)
+        );
+        assert_eq!(info.name, "PFU Limited HHKB-Studio");
+        assert!(parse_uevent(PathBuf::from("/dev/hidraw1"), "HID_NAME=foo\n").is_none());
+    }
+}
@@ -0,0 +1,167 @@
+//! Loader of TOML profile files which may include other files.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+
+use crate::keymap::{self, TomlLayers};
+
+/// Parses TOML profile data, or data of consecutive layers. The included
+/// files are resolved relative to the `base_dir`.
+///
+/// Errors are reported with the source location and the key position.
+pub fn parse_toml_string_at(serialized: &str, base_dir: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut stack = Vec::new();
+    let parsed = parse_toml_layers_at(serialized, base_dir, &mut stack)?;
+    keymap::layers_to_profile_data(&parsed.layers)
+}
+
+/// Returns the directory where the included files of the `path` are resolved.
+pub fn base_dir_of(maybe_path: Option<&Path>) -> &Path {
+    match maybe_path.and_then(|path| path.parent()) {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+fn parse_toml_layers_at(
+    serialized: &str,
+    base_dir: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> anyhow::Result<TomlLayers> {
+    keymap::parse_toml_layers(serialized, &mut |name| {
+        let path = base_dir.join(name);
+        let canonical_path = path
+            .canonicalize()
+            .with_context(|| format!("failed to resolve {}", path.display()))?;
+        if let Some(pos) = stack.iter().position(|p| *p == canonical_path) {
+            let cycle: Vec<_> = stack[pos..]
+                .iter()
+                .chain([&canonical_path])
+                .map(|p| p.display().to_string())
+                .collect();
+            anyhow::bail!("include cycle: {}", cycle.join(" -> "));
+        }
+        let serialized = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        stack.push(canonical_path);
+        let res = parse_toml_layers_at(&serialized, base_dir_of(Some(&path)), stack);
+        stack.pop();
+        Ok(res?.named_layers)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::LAYER_DATA_LEN;
+    use crate::layout;
+
+    /// Creates temporary directory containing the `files`.
+    fn create_files(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, content) in files {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    fn parse_file(dir: &Path, name: &str) -> anyhow::Result<Vec<u8>> {
+        let path = dir.join(name);
+        let serialized = fs::read_to_string(&path).unwrap();
+        parse_toml_string_at(&serialized, base_dir_of(Some(&path)))
+    }
+
+    /// Returns the scancode at the key `position` of the layer.
+    fn scancode_at(profile_data: &[u8], layer_index: usize, position: &str) -> u16 {
+        let offset = layer_index * LAYER_DATA_LEN + layout::position_index(position).unwrap() * 2;
+        u16::from_be_bytes([profile_data[offset], profile_data[offset + 1]])
+    }
+
+    const PROFILE_INHERITING_HOUSE: &str = "\
+[[layers]]
+inherits = \"transparent\"
+
+[[layers]]
+inherits = \"house\"
+";
+
+    #[test]
+    fn inherit_named_layer_from_nested_include() {
+        let profile = format!("include = [\"shared/fn1.toml\"]\n{PROFILE_INHERITING_HOUSE}");
+        let dir = create_files(&[
+            ("profile.toml", &profile),
+            (
+                "shared/fn1.toml",
+                "include = [\"keys/arrows.toml\"]\n\
+                 [[layers]]\nname = \"house\"\ninherits = \"arrows\"\n\
+                 [layers.keys]\nQuote = 0x004f\n",
+            ),
+            (
+                "shared/keys/arrows.toml",
+                "[[layers]]\nname = \"arrows\"\ninherits = \"transparent\"\n\
+                 [layers.keys]\nSemicolon = 0x0052\n",
+            ),
+        ]);
+        let data = parse_file(dir.path(), "profile.toml").unwrap();
+        assert_eq!(scancode_at(&data, 1, "Semicolon"), 0x0052);
+        assert_eq!(scancode_at(&data, 1, "Quote"), 0x004f);
+        assert_eq!(scancode_at(&data, 1, "A"), 0x0000);
+    }
+
+    #[test]
+    fn later_include_overrides_named_layer() {
+        let named_layer = |code: u16| {
+            format!(
+                "[[layers]]\nname = \"house\"\ninherits = \"transparent\"\n\
+                 [layers.keys]\nSemicolon = 0x{code:04x}\n"
+            )
+        };
+        let profile = format!("include = [\"a.toml\", \"b.toml\"]\n{PROFILE_INHERITING_HOUSE}");
+        let dir = create_files(&[
+            ("profile.toml", &profile),
+            ("a.toml", &named_layer(0x0052)),
+            ("b.toml", &named_layer(0x0051)),
+        ]);
+        let data = parse_file(dir.path(), "profile.toml").unwrap();
+        assert_eq!(scancode_at(&data, 1, "Semicolon"), 0x0051);
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = create_files(&[
+            ("a.toml", "include = [\"b.toml\"]\n"),
+            ("b.toml", "include = [\"a.toml\"]\n"),
+            ("self.toml", "include = [\"self.toml\"]\n"),
+        ]);
+        let dir_path = dir.path().canonicalize().unwrap();
+        let path = |name: &str| dir_path.join(name).display().to_string();
+        let err = parse_file(dir.path(), "a.toml").unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            format!(
+                "line 1, column 12: failed to include \"b.toml\": \
+                 line 1, column 12: failed to include \"a.toml\": \
+                 line 1, column 12: failed to include \"b.toml\": \
+                 include cycle: {} -> {} -> {}",
+                path("b.toml"),
+                path("a.toml"),
+                path("b.toml")
+            )
+        );
+        let err = parse_file(dir.path(), "self.toml").unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            format!(
+                "line 1, column 12: failed to include \"self.toml\": \
+                 line 1, column 12: failed to include \"self.toml\": \
+                 include cycle: {} -> {}",
+                path("self.toml"),
+                path("self.toml")
+            )
+        );
+    }
+}
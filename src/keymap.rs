@@ -1,16 +1,173 @@
 //! Utility to process keymap data.
 
-use std::fmt::Write as _;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write as _};
+use std::ops::Range;
+use std::sync::OnceLock;
+
+use crate::{layout, scancode};
 
 pub const LAYER_DATA_LEN: usize = 0xf0;
 pub const PROFILE_DATA_LEN: usize = LAYER_DATA_LEN * 4;
 
+/// Version of the profile file format. Files without `format_version` are of
+/// version 1.
+pub const FORMAT_VERSION: i64 = 1;
+
+/// Migrations of TOML document. `MIGRATIONS[i]` upgrades version `i + 1` to
+/// `i + 2`.
+type Migration = fn(&mut toml_edit::DocumentMut) -> anyhow::Result<()>;
+const MIGRATIONS: [Migration; FORMAT_VERSION as usize - 1] = [];
+
+/// Scancode which falls through to the lower layer.
+pub const TRANSPARENT_SCANCODE: u16 = 0x0000;
+
+/// Keymap layer in profile.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum Layer {
+    Base,
+    Fn1,
+    Fn2,
+    Fn3,
+}
+
+impl Layer {
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Looks up layer by lowercase name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        <Self as clap::ValueEnum>::from_str(name, false).ok()
+    }
+
+    /// Returns lowercase name.
+    pub fn name(self) -> &'static str {
+        match self {
+            Layer::Base => "base",
+            Layer::Fn1 => "fn1",
+            Layer::Fn2 => "fn2",
+            Layer::Fn3 => "fn3",
+        }
+    }
+
+    pub fn from_index(index: usize) -> Option<Self> {
+        [Layer::Base, Layer::Fn1, Layer::Fn2, Layer::Fn3]
+            .get(index)
+            .copied()
+    }
+
+    /// Byte range of the layer in profile data.
+    pub fn data_range(self) -> Range<usize> {
+        let start = self.index() * LAYER_DATA_LEN;
+        start..start + LAYER_DATA_LEN
+    }
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Layer::Base => "Base",
+            Layer::Fn1 => "Fn1",
+            Layer::Fn2 => "Fn2",
+            Layer::Fn3 => "Fn3",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Splits profile data into scancodes per layer.
+pub fn layers_scancodes(profile_data: &[u8]) -> Vec<Vec<u16>> {
+    assert!(profile_data.len().is_multiple_of(LAYER_DATA_LEN));
+    profile_data
+        .chunks_exact(LAYER_DATA_LEN)
+        .map(|data| {
+            data.chunks_exact(2)
+                .map(|d| u16::from_be_bytes(d.try_into().unwrap()))
+                .collect()
+        })
+        .collect()
+}
+
+/// Returns the layer activated while the key of the `code` is held.
+pub fn activated_layer(code: u16) -> Option<Layer> {
+    match code {
+        0x5101 => Some(Layer::Fn1),
+        0x5102 => Some(Layer::Fn2),
+        0x5103 => Some(Layer::Fn3),
+        _ => None,
+    }
+}
+
+/// Returns the scancode at the `index` of the layer. Transparent keys of Fn
+/// layers fall through to the base layer.
+pub fn effective_scancode(layers_scancodes: &[Vec<u16>], layer_index: usize, index: usize) -> u16 {
+    match layers_scancodes[layer_index][index] {
+        TRANSPARENT_SCANCODE => layers_scancodes[0][index],
+        code => code,
+    }
+}
+
+/// Returns the keys activating layers: `(from_layer, position, to_layer)`.
+pub fn layer_activations(layers_scancodes: &[Vec<u16>]) -> Vec<(Layer, usize, Layer)> {
+    let mut activations = Vec::new();
+    for (i, scancodes) in layers_scancodes.iter().enumerate() {
+        let Some(from_layer) = Layer::from_index(i) else {
+            continue;
+        };
+        for j in 0..scancodes.len() {
+            let code = effective_scancode(layers_scancodes, i, j);
+            if let Some(to_layer) = activated_layer(code) {
+                activations.push((from_layer, j, to_layer));
+            }
+        }
+    }
+    activations
+}
+
+/// Returns flags of layers which can be activated from the base layer.
+pub fn reachable_layers(layers_scancodes: &[Vec<u16>]) -> Vec<bool> {
+    let activations = layer_activations(layers_scancodes);
+    let mut reachable = vec![false; layers_scancodes.len()];
+    reachable[0] = true;
+    let mut pending = vec![Layer::Base];
+    while let Some(layer) = pending.pop() {
+        for &(_, _, to_layer) in activations.iter().filter(|(from, _, _)| *from == layer) {
+            if !reachable[to_layer.index()] {
+                reachable[to_layer.index()] = true;
+                pending.push(to_layer);
+            }
+        }
+    }
+    reachable
+}
+
+/// Serializes profile data, or data of consecutive layers.
 pub fn serialize_to_toml_string(profile_data: &[u8]) -> String {
-    assert_eq!(profile_data.len(), PROFILE_DATA_LEN);
-    let mut buffer = String::new();
-    for layer_data in profile_data.chunks_exact(LAYER_DATA_LEN) {
+    serialize_to_toml_string_impl(profile_data, None)
+}
+
+/// Serializes data of consecutive layers starting from the `first_layer`,
+/// with comments describing the layers and the scancode labels.
+pub fn serialize_to_annotated_toml_string(profile_data: &[u8], first_layer: Layer) -> String {
+    serialize_to_toml_string_impl(profile_data, Some(first_layer))
+}
+
+fn serialize_to_toml_string_impl(profile_data: &[u8], first_layer: Option<Layer>) -> String {
+    assert!(profile_data.len().is_multiple_of(LAYER_DATA_LEN));
+    let mut buffer = format!("format_version = {FORMAT_VERSION}\n\n");
+    for (i, layer_data) in profile_data.chunks_exact(LAYER_DATA_LEN).enumerate() {
+        let annotate = if let Some(first_layer) = first_layer {
+            let index = first_layer.index() + i;
+            if let Some(layer) = Layer::from_index(index) {
+                writeln!(buffer, "# {layer}").unwrap();
+            }
+            true
+        } else {
+            false
+        };
         buffer.push_str("[[layers]]\nscancodes = ");
-        serialize_layer_scancodes_to_toml_string(&mut buffer, layer_data);
+        serialize_layer_scancodes_to_toml_string(&mut buffer, layer_data, annotate);
         buffer.push('\n');
     }
     buffer.truncate(buffer.trim_end_matches('\n').len() + 1);
@@ -18,47 +175,795 @@ pub fn serialize_to_toml_string(profile_data: &[u8]) -> String {
     buffer
 }
 
-fn serialize_layer_scancodes_to_toml_string(buffer: &mut String, layer_data: &[u8]) {
-    let scancodes = layer_data
+/// Serializes profile data in minimal form. Layers other than the base
+/// layer are stored as differences from the base or transparent layer.
+pub fn serialize_to_sparse_toml_string(profile_data: &[u8]) -> String {
+    assert_eq!(profile_data.len(), PROFILE_DATA_LEN);
+    let layers_scancodes = layers_scancodes(profile_data);
+    let base_scancodes = &layers_scancodes[0];
+    let transparent_scancodes = vec![TRANSPARENT_SCANCODE; LAYER_DATA_LEN / 2];
+    let mut buffer = format!("format_version = {FORMAT_VERSION}\n\n");
+    buffer.push_str("[[layers]]\nscancodes = ");
+    serialize_layer_scancodes_to_toml_string(&mut buffer, &profile_data[..LAYER_DATA_LEN], false);
+    for scancodes in &layers_scancodes[1..] {
+        let count_diff =
+            |other: &[u16]| scancodes.iter().zip(other).filter(|(a, b)| a != b).count();
+        let (inherits, inherited_scancodes) =
+            if count_diff(base_scancodes) < count_diff(&transparent_scancodes) {
+                (Layer::Base.name(), base_scancodes)
+            } else {
+                ("transparent", &transparent_scancodes)
+            };
+        writeln!(buffer, "\n[[layers]]\ninherits = \"{inherits}\"").unwrap();
+        let mut overrides = scancodes
+            .iter()
+            .zip(inherited_scancodes)
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .peekable();
+        if overrides.peek().is_some() {
+            buffer.push_str("[layers.keys]\n");
+        }
+        for (j, (code, _)) in overrides {
+            writeln!(buffer, "{} = 0x{code:04x}", layout::position_name(j)).unwrap();
+        }
+    }
+    debug_assert!(buffer.parse::<toml::Table>().is_ok());
+    buffer
+}
+
+/// Serializes scancodes array of the layer in canonical layout.
+pub fn serialize_layer_scancodes_to_toml_string(
+    buffer: &mut String,
+    layer_data: &[u8],
+    annotate: bool,
+) {
+    let scancodes: Vec<u16> = layer_data
         .chunks_exact(2)
-        .map(|d| u16::from_be_bytes(d.try_into().unwrap()));
+        .map(|d| u16::from_be_bytes(d.try_into().unwrap()))
+        .collect();
     // Build formatted array split per keyboard raw.
     buffer.push_str("[\n");
-    for (i, code) in scancodes.enumerate() {
-        if i % 15 == 0 {
-            buffer.push_str("  ");
-        }
-        write!(buffer, "0x{code:04x},").unwrap();
-        buffer.push(if i % 15 == 14 { '\n' } else { ' ' });
+    for codes in scancodes.chunks(15) {
+        format_scancodes_row(buffer, codes, None, annotate);
     }
     buffer.push_str("]\n");
 }
 
-pub fn parse_toml_string(serialized: &str) -> anyhow::Result<Vec<u8>> {
-    let doc: toml::Table = serialized.parse()?;
+/// Formats line of the scancodes array row in canonical layout, followed by
+/// the `comment`, and the annotation line if `annotate` is set.
+pub fn format_scancodes_row(
+    buffer: &mut String,
+    codes: &[u16],
+    comment: Option<&str>,
+    annotate: bool,
+) {
+    buffer.push_str("  ");
+    for (i, code) in codes.iter().enumerate() {
+        write!(buffer, "0x{code:04x},").unwrap();
+        if i != codes.len() - 1 {
+            buffer.push(' ');
+        }
+    }
+    if let Some(comment) = comment {
+        write!(buffer, " {comment}").unwrap();
+    }
+    buffer.push('\n');
+    if let Some(line) = annotate.then(|| annotation_line(codes)).flatten() {
+        buffer.push_str(&line);
+        buffer.push('\n');
+    }
+}
+
+/// Width of label cell in annotation comment, which is the width of
+/// "0x....," and the separator.
+const ANNOTATION_CELL_WIDTH: usize = 8;
+
+/// Formats comment line describing the scancodes labels, or returns `None`
+/// if none of the scancodes has label.
+pub fn annotation_line(codes: &[u16]) -> Option<String> {
+    // Align labels under "0x....," of the row indented by 2 columns
+    let mut line = String::from("#");
+    for code in codes {
+        let label = scancode::scancode_to_label(*code).unwrap_or("");
+        let label: String = label.chars().take(ANNOTATION_CELL_WIDTH - 1).collect();
+        write!(line, " {label:7}").unwrap();
+    }
+    let line = line.trim_end();
+    (line != "#").then(|| line.to_owned())
+}
+
+/// Checks if the comment line looks like the one generated by
+/// [`annotation_line()`] for a row of up to `num_codes` scancodes.
+pub fn is_annotation_line(line: &str, num_codes: usize) -> bool {
+    static LABELS: OnceLock<HashSet<String>> = OnceLock::new();
+    let labels = LABELS.get_or_init(|| {
+        (0..=u16::MAX)
+            .filter_map(scancode::scancode_to_label)
+            .map(|label| label.chars().take(ANNOTATION_CELL_WIDTH - 1).collect())
+            .collect()
+    });
+    let Some(cells) = line.strip_prefix('#') else {
+        return false;
+    };
+    let cells: Vec<char> = cells.chars().collect();
+    let cells: Vec<String> = cells
+        .chunks(ANNOTATION_CELL_WIDTH)
+        .map(|cell| cell.iter().collect())
+        .collect();
+    !cells.is_empty()
+        && cells.len() <= num_codes
+        && cells.iter().all(|cell| {
+            let label = cell.trim_end();
+            cell.starts_with(' ') && (label.is_empty() || labels.contains(&label[1..]))
+        })
+}
+
+/// Named layers which can be inherited by other layers.
+pub type NamedLayers = HashMap<String, Vec<u16>>;
+
+/// Layers parsed from TOML profile or fragment file.
+#[derive(Clone, Debug, Default)]
+pub struct TomlLayers {
+    /// Scancodes of the profile layers.
+    pub layers: Vec<Vec<u16>>,
+    /// Named layers defined in the file and the included files.
+    pub named_layers: NamedLayers,
+}
+
+/// Flattens layers scancodes into profile data.
+pub fn layers_to_profile_data(layers: &[Vec<u16>]) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(!layers.is_empty(), "no layers");
+    let profile_data: Vec<u8> = layers
+        .iter()
+        .flatten()
+        .flat_map(|code| code.to_be_bytes())
+        .collect();
+    assert!(profile_data.len().is_multiple_of(LAYER_DATA_LEN));
+    Ok(profile_data)
+}
+
+/// Parses TOML profile or fragment file which may have no layers.
+///
+/// A layer may be sparse. It consists of `keys` table overriding the
+/// scancodes at the named positions, and the `inherits` layer ("base", "fn1",
+/// etc., named layer, or "transparent" (0x0000)) which fills the other
+/// scancodes.
+///
+/// The files listed in `include` are loaded by `load_include()`, which
+/// returns the named layers of the included file. Layers having `name` can be
+/// inherited by name.
+pub fn parse_toml_layers(
+    serialized: &str,
+    load_include: &mut dyn FnMut(&str) -> anyhow::Result<NamedLayers>,
+) -> anyhow::Result<TomlLayers> {
+    parse_toml_layers_with(serialized, &MIGRATIONS, load_include)
+}
+
+/// Parses TOML profile or fragment file. The `migrations` define the current
+/// format version.
+fn parse_toml_layers_with(
+    serialized: &str,
+    migrations: &[Migration],
+    load_include: &mut dyn FnMut(&str) -> anyhow::Result<NamedLayers>,
+) -> anyhow::Result<TomlLayers> {
+    let doc = toml_edit::ImDocument::parse(serialized)?;
+    let source = SourceText::new(serialized);
+    let current_version = i64::try_from(migrations.len()).unwrap() + 1;
+    let version = parse_format_version(&source, doc.get("format_version"), current_version)?;
+    if version < current_version {
+        // Upgrade in memory. Errors are reported at the lines of the original
+        // text.
+        let mut doc: toml_edit::DocumentMut = serialized.parse()?;
+        upgrade_toml_document_with(&mut doc, version, migrations)?;
+        let upgraded = doc.to_string();
+        let doc = toml_edit::ImDocument::parse(upgraded.as_str())?;
+        let source = SourceText::upgraded(&upgraded, serialized);
+        return parse_toml_document(&doc, &source, load_include);
+    }
+    parse_toml_document(&doc, &source, load_include)
+}
+
+/// Parses TOML document of the current format version.
+fn parse_toml_document(
+    doc: &toml_edit::ImDocument<&str>,
+    source: &SourceText,
+    load_include: &mut dyn FnMut(&str) -> anyhow::Result<NamedLayers>,
+) -> anyhow::Result<TomlLayers> {
+    let mut named_layers = NamedLayers::new();
+    if let Some(item) = doc.get("include") {
+        let paths = item
+            .as_array()
+            .ok_or_else(|| source.error(item.span(), "unexpected type of include"))?;
+        for value in paths {
+            let path = value
+                .as_str()
+                .ok_or_else(|| source.error(value.span(), "unexpected type of include path"))?;
+            let included = load_include(path).map_err(|err| {
+                let message = source.error(value.span(), &format!("failed to include {path:?}"));
+                err.context(message.to_string())
+            })?;
+            for (name, scancodes) in included {
+                // Overridden by the later includes
+                named_layers.insert(name, scancodes);
+            }
+        }
+    }
+
+    let Some(layers_item) = doc.get("layers") else {
+        return Ok(TomlLayers {
+            layers: vec![],
+            named_layers,
+        });
+    };
+    let layers: Vec<(Option<Range<usize>>, &dyn toml_edit::TableLike)> =
+        if let Some(tables) = layers_item.as_array_of_tables() {
+            tables
+                .iter()
+                .map(|table| (table.span(), table as &dyn toml_edit::TableLike))
+                .collect()
+        } else if let Some(array) = layers_item.as_array() {
+            array
+                .iter()
+                .map(|value| {
+                    let table = value
+                        .as_inline_table()
+                        .ok_or_else(|| source.error(value.span(), "unexpected type of layer"))?;
+                    Ok((value.span(), table as &dyn toml_edit::TableLike))
+                })
+                .collect::<anyhow::Result<_>>()?
+        } else {
+            return Err(source.error(layers_item.span(), "unexpected type of layers"));
+        };
+
+    let mut layers_scancodes: Vec<Vec<u16>> = Vec::with_capacity(layers.len());
+    for (i, (layer_span, table)) in layers.into_iter().enumerate() {
+        let scancodes = parse_layer(
+            source,
+            i,
+            layer_span,
+            table,
+            &layers_scancodes,
+            &named_layers,
+        )?;
+        if let Some(item) = table.get("name") {
+            let name = item
+                .as_str()
+                .filter(|name| *name != "transparent" && Layer::from_name(name).is_none())
+                .ok_or_else(|| source.error(item.span(), "invalid layer name"))?;
+            named_layers.insert(name.to_owned(), scancodes.clone());
+        }
+        layers_scancodes.push(scancodes);
+    }
+
+    Ok(TomlLayers {
+        layers: layers_scancodes,
+        named_layers,
+    })
+}
+
+fn parse_format_version(
+    source: &SourceText,
+    maybe_item: Option<&toml_edit::Item>,
+    current_version: i64,
+) -> anyhow::Result<i64> {
+    let Some(item) = maybe_item else {
+        return Ok(1);
+    };
+    let version = item
+        .as_integer()
+        .filter(|&n| n >= 1)
+        .ok_or_else(|| source.error(item.span(), "invalid format_version"))?;
+    if version > current_version {
+        let message = format!(
+            "format_version {version} is newer than the supported version {current_version}"
+        );
+        return Err(source.error(item.span(), &message));
+    }
+    Ok(version)
+}
+
+/// Applies migrations to the TOML document of the `version` to upgrade it to
+/// the current format version. `format_version` isn't updated.
+pub fn upgrade_toml_document(doc: &mut toml_edit::DocumentMut, version: i64) -> anyhow::Result<()> {
+    upgrade_toml_document_with(doc, version, &MIGRATIONS)
+}
+
+fn upgrade_toml_document_with(
+    doc: &mut toml_edit::DocumentMut,
+    version: i64,
+    migrations: &[Migration],
+) -> anyhow::Result<()> {
+    let first = usize::try_from(version - 1).unwrap();
+    for migrate in &migrations[first..] {
+        migrate(doc)?;
+    }
+    Ok(())
+}
+
+fn parse_layer(
+    source: &SourceText,
+    index: usize,
+    layer_span: Option<Range<usize>>,
+    table: &dyn toml_edit::TableLike,
+    prev_layers_scancodes: &[Vec<u16>],
+    named_layers: &NamedLayers,
+) -> anyhow::Result<Vec<u16>> {
+    let layer_name = describe_layer(index);
+    let mut scancodes = if let Some(item) = table.get("scancodes") {
+        let array = item.as_array().ok_or_else(|| {
+            source.error(
+                item.span(),
+                &format!("{layer_name}: unexpected type of scancodes"),
+            )
+        })?;
+        check_scancodes_count(source, &layer_name, array)?;
+        array
+            .iter()
+            .enumerate()
+            .map(|(j, value)| {
+                value
+                    .as_integer()
+                    .and_then(|n| u16::try_from(n).ok())
+                    .ok_or_else(|| {
+                        let position = layout::describe_position(j);
+                        let message = if value.is_integer() {
+                            format!("{layer_name}, {position}: scancode out of range")
+                        } else {
+                            format!("{layer_name}, {position}: unexpected scancode value")
+                        };
+                        source.error(value.span(), &message)
+                    })
+            })
+            .collect::<anyhow::Result<_>>()?
+    } else if let Some(item) = table.get("inherits") {
+        let name = item.as_str().ok_or_else(|| {
+            source.error(
+                item.span(),
+                &format!("{layer_name}: unexpected type of inherits"),
+            )
+        })?;
+        if name == "transparent" {
+            vec![TRANSPARENT_SCANCODE; LAYER_DATA_LEN / 2]
+        } else {
+            Layer::from_name(name)
+                .and_then(|layer| prev_layers_scancodes.get(layer.index()))
+                .or_else(|| named_layers.get(name))
+                .ok_or_else(|| {
+                    source.error(
+                        item.span(),
+                        &format!("{layer_name}: cannot inherit from {name:?}"),
+                    )
+                })?
+                .clone()
+        }
+    } else if table.contains_key("keys") {
+        vec![TRANSPARENT_SCANCODE; LAYER_DATA_LEN / 2]
+    } else {
+        return Err(source.error(layer_span, &format!("{layer_name}: scancodes not found")));
+    };
+
+    if let Some(item) = table.get("keys") {
+        let keys = item.as_table_like().ok_or_else(|| {
+            source.error(
+                item.span(),
+                &format!("{layer_name}: unexpected type of keys"),
+            )
+        })?;
+        for (key, value) in keys.get_values() {
+            let key = key.last().unwrap();
+            let j = layout::position_index(key.get()).ok_or_else(|| {
+                let message = format!("{layer_name}: unknown key position {:?}", key.get());
+                source.error(key.span(), &message)
+            })?;
+            let code = if let Some(label) = value.as_str() {
+                scancode::parse_scancode(label)
+            } else {
+                value.as_integer().and_then(|n| u16::try_from(n).ok())
+            };
+            scancodes[j] = code.ok_or_else(|| {
+                let position = layout::describe_position(j);
+                let message = format!("{layer_name}, {position}: invalid scancode value");
+                source.error(value.span(), &message)
+            })?;
+        }
+    }
+    Ok(scancodes)
+}
+
+fn describe_layer(index: usize) -> String {
+    if let Some(layer) = Layer::from_index(index) {
+        format!("layer #{index} ({layer})")
+    } else {
+        format!("layer #{index}")
+    }
+}
+
+/// Reports mismatch of the number of scancodes, and locates the short or
+/// long row if the array is formatted per keyboard row.
+///
+/// The array is considered formatted per keyboard row only if it spans the
+/// expected number of lines, and all lines but one have 15 values.
+fn check_scancodes_count(
+    source: &SourceText,
+    layer_name: &str,
+    array: &toml_edit::Array,
+) -> anyhow::Result<()> {
+    let expected_len = LAYER_DATA_LEN / 2;
+    if array.len() == expected_len {
+        return Ok(());
+    }
+    let message = format!(
+        "{layer_name}: unexpected number of scancodes (expected {expected_len}, got {})",
+        array.len()
+    );
+    // Group values by source line
+    let mut rows: Vec<(usize, Vec<&toml_edit::Value>)> = Vec::new();
+    for value in array {
+        let Some(span) = value.span() else {
+            return Err(source.error(array.span(), &message));
+        };
+        let (line, _) = source.line_column(span.start);
+        match rows.last_mut() {
+            Some((last_line, values)) if *last_line == line => values.push(value),
+            _ => rows.push((line, vec![value])),
+        }
+    }
+    let mut odd_rows = rows
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, values))| values.len() != 15);
+    if let (Some((row, (_, values))), None, true) = (
+        odd_rows.next(),
+        odd_rows.next(),
+        rows.len() == expected_len / 15,
+    ) {
+        let span = values.first().and_then(|v| v.span());
+        let row_message = format!("row #{row} has {} scancodes (expected 15)", values.len());
+        return Err(source.error(span, &format!("{message}: {row_message}")));
+    }
+    Err(source.error(array.span(), &message))
+}
+
+/// Source text to translate byte offset to line and column.
+struct SourceText<'a> {
+    text: &'a str,
+    /// Line numbers in the original text for each line of the upgraded text.
+    original_lines: Option<Vec<Option<usize>>>,
+}
+
+impl<'a> SourceText<'a> {
+    fn new(text: &'a str) -> Self {
+        SourceText {
+            text,
+            original_lines: None,
+        }
+    }
+
+    /// Creates source of the `upgraded` text, which reports locations in
+    /// the `original` text.
+    fn upgraded(upgraded: &'a str, original: &str) -> Self {
+        SourceText {
+            text: upgraded,
+            original_lines: Some(match_lines(upgraded, original)),
+        }
+    }
+
+    /// Returns 1-based line and column numbers.
+    fn line_column(&self, offset: usize) -> (usize, usize) {
+        let before = &self.text[..offset];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |p| p + 1);
+        let column = before[line_start..].chars().count() + 1;
+        (line, column)
+    }
+
+    fn error(&self, span: Option<Range<usize>>, message: &str) -> anyhow::Error {
+        let Some(span) = span else {
+            return anyhow::anyhow!("{message}");
+        };
+        let (line, column) = self.line_column(span.start);
+        match &self.original_lines {
+            None => anyhow::anyhow!("line {line}, column {column}: {message}"),
+            Some(lines) => match lines[line - 1] {
+                Some(line) => anyhow::anyhow!("line {line}, column {column}: {message}"),
+                None => anyhow::anyhow!("{message} (at line changed by format upgrade)"),
+            },
+        }
+    }
+}
+
+/// Maps each line of the text `a` to the 1-based line number of the same
+/// line in the text `b`, by the longest common subsequence of the lines.
+fn match_lines(a: &str, b: &str) -> Vec<Option<usize>> {
+    let a: Vec<&str> = a.split('\n').collect();
+    let b: Vec<&str> = b.split('\n').collect();
+    // lengths[i][j]: length of the LCS of a[i..] and b[j..]
+    let mut lengths = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    let mut lines = vec![None; a.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            lines[i] = Some(j + 1);
+            (i, j) = (i + 1, j + 1);
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Serializes profile data, or data of consecutive layers, in the same
+/// structure as TOML.
+pub fn serialize_to_json_string(profile_data: &[u8]) -> String {
+    assert!(profile_data.len().is_multiple_of(LAYER_DATA_LEN));
+    let mut buffer = String::new();
+    writeln!(buffer, "{{\n  \"format_version\": {FORMAT_VERSION},").unwrap();
+    buffer.push_str("  \"layers\": [\n");
+    for (i, layer_data) in profile_data.chunks_exact(LAYER_DATA_LEN).enumerate() {
+        if i > 0 {
+            buffer.push_str(",\n");
+        }
+        buffer.push_str("    {\n      \"scancodes\": [\n");
+        let scancodes = layer_data
+            .chunks_exact(2)
+            .map(|d| u16::from_be_bytes(d.try_into().unwrap()));
+        // Build formatted array split per keyboard raw. Numbers are decimal
+        // since JSON doesn't support hexadecimal literals.
+        for (i, code) in scancodes.enumerate() {
+            if i % 15 == 0 {
+                buffer.push_str("        ");
+            }
+            write!(buffer, "{code:5}").unwrap();
+            if i + 1 < LAYER_DATA_LEN / 2 {
+                buffer.push(',');
+            }
+            buffer.push(if i % 15 == 14 { '\n' } else { ' ' });
+        }
+        buffer.push_str("      ]\n    }");
+    }
+    buffer.push_str("\n  ]\n}\n");
+    debug_assert!(serde_json::from_str::<serde_json::Value>(&buffer).is_ok());
+    buffer
+}
+
+/// Parses profile data, or data of consecutive layers.
+pub fn parse_json_string(serialized: &str) -> anyhow::Result<Vec<u8>> {
+    let doc: serde_json::Value = serde_json::from_str(serialized)?;
+    if let Some(value) = doc.get("format_version") {
+        let version = value
+            .as_i64()
+            .filter(|&n| n >= 1)
+            .ok_or_else(|| anyhow::anyhow!("invalid format_version"))?;
+        anyhow::ensure!(
+            version <= FORMAT_VERSION,
+            "format_version {version} is newer than the supported version {FORMAT_VERSION}"
+        );
+    }
     let layers = doc
         .get("layers")
         .ok_or_else(|| anyhow::anyhow!("layers not found"))?
         .as_array()
         .ok_or_else(|| anyhow::anyhow!("unexpected type of layers"))?;
-    anyhow::ensure!(layers.len() == 4, "unexpected number of layers");
+    anyhow::ensure!(!layers.is_empty(), "no layers");
 
-    let mut profile_data = Vec::with_capacity(PROFILE_DATA_LEN);
-    for layer in layers {
-        let scancodes: Vec<u16> = layer
+    let mut profile_data = Vec::with_capacity(LAYER_DATA_LEN * layers.len());
+    for (i, layer) in layers.iter().enumerate() {
+        let layer_name = describe_layer(i);
+        let scancodes = layer
             .get("scancodes")
-            .ok_or_else(|| anyhow::anyhow!("scancodes not found"))?
-            .clone()
-            .try_into()?;
+            .ok_or_else(|| anyhow::anyhow!("{layer_name}: scancodes not found"))?
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("{layer_name}: unexpected type of scancodes"))?;
         anyhow::ensure!(
             scancodes.len() == LAYER_DATA_LEN / 2,
-            "unexpected number of scancodes"
+            "{layer_name}: unexpected number of scancodes (expected {}, got {})",
+            LAYER_DATA_LEN / 2,
+            scancodes.len()
         );
-        for code in &scancodes {
+        for (j, value) in scancodes.iter().enumerate() {
+            let code = value
+                .as_u64()
+                .and_then(|n| u16::try_from(n).ok())
+                .ok_or_else(|| {
+                    let position = layout::describe_position(j);
+                    anyhow::anyhow!("{layer_name}, {position}: invalid scancode {value}")
+                })?;
             profile_data.extend(code.to_be_bytes());
         }
     }
 
-    assert_eq!(profile_data.len(), PROFILE_DATA_LEN);
+    assert!(profile_data.len().is_multiple_of(LAYER_DATA_LEN));
     Ok(profile_data)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_layers(serialized: &str) -> anyhow::Result<Vec<Vec<u16>>> {
+        let mut load_include = |path: &str| anyhow::bail!("unexpected include {path:?}");
+        Ok(parse_toml_layers(serialized, &mut load_include)?.layers)
+    }
+
+    /// Formats `[[layers]]` table of the scancodes array split into lines.
+    fn layer_with_lines(lines: &[usize]) -> String {
+        let mut buffer = String::from("[[layers]]\nscancodes = [\n");
+        for &n in lines {
+            buffer.push_str(&"0x0004, ".repeat(n));
+            buffer.push('\n');
+        }
+        buffer.push_str("]\n");
+        buffer
+    }
+
+    #[test]
+    fn scancodes_count_names_short_row() {
+        let err = parse_layers(&layer_with_lines(&[15, 14, 15, 15, 15, 15, 15, 15])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 4, column 1: layer #0 (Base): unexpected number of scancodes \
+             (expected 120, got 119): row #1 has 14 scancodes (expected 15)"
+        );
+    }
+
+    #[test]
+    fn scancodes_count_of_single_line_array() {
+        let err = parse_layers(&layer_with_lines(&[119])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2, column 13: layer #0 (Base): unexpected number of scancodes \
+             (expected 120, got 119)"
+        );
+    }
+
+    #[test]
+    fn scancodes_count_of_wrapped_row() {
+        let mut serialized = layer_with_lines(&[2, 13, 14, 15, 15, 15, 15, 15, 15]);
+        serialized = serialized.replacen(", \n", ", # esc and 1\n", 1);
+        let err = parse_layers(&serialized).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2, column 13: layer #0 (Base): unexpected number of scancodes \
+             (expected 120, got 119)"
+        );
+    }
+
+    /// Test migration which renames `keymap` to `layers`.
+    fn rename_keymap(doc: &mut toml_edit::DocumentMut) -> anyhow::Result<()> {
+        if let Some(item) = doc.remove("keymap") {
+            doc.insert("layers", item);
+        }
+        Ok(())
+    }
+
+    fn parse_layers_with_test_migration(serialized: &str) -> anyhow::Result<Vec<Vec<u16>>> {
+        let mut load_include = |path: &str| anyhow::bail!("unexpected include {path:?}");
+        let migrations: [Migration; 1] = [rename_keymap];
+        Ok(parse_toml_layers_with(serialized, &migrations, &mut load_include)?.layers)
+    }
+
+    #[test]
+    fn parse_upgrades_older_version() {
+        let layer = layer_with_lines(&[15; 8]);
+        let expected = vec![vec![0x0004; LAYER_DATA_LEN / 2]];
+        let v1 = layer.replace("[[layers]]", "[[keymap]]");
+        for serialized in [v1.clone(), format!("format_version = 1\n{v1}")] {
+            assert_eq!(
+                parse_layers_with_test_migration(&serialized).unwrap(),
+                expected
+            );
+        }
+        let v2 = format!("format_version = 2\n{layer}");
+        assert_eq!(parse_layers_with_test_migration(&v2).unwrap(), expected);
+        let v3 = format!("format_version = 3\n{layer}");
+        assert_eq!(
+            parse_layers_with_test_migration(&v3)
+                .unwrap_err()
+                .to_string(),
+            "line 1, column 18: format_version 3 is newer than the supported version 2"
+        );
+    }
+
+    #[test]
+    fn parse_upgraded_reports_original_lines() {
+        let layer = layer_with_lines(&[15; 8]).replacen("0x0004", "0x10000", 1);
+        let v1 = format!("# header\n\n{}", layer.replace("[[layers]]", "[[keymap]]"));
+        assert_eq!(
+            parse_layers_with_test_migration(&v1)
+                .unwrap_err()
+                .to_string(),
+            "line 5, column 1: layer #0 (Base), key Esc (row #0, column #0): scancode out of range"
+        );
+    }
+
+    #[test]
+    fn match_lines_by_common_subsequence() {
+        assert_eq!(
+            match_lines("a\nx\nb\nc", "a\nb\ny\nc"),
+            [Some(1), None, Some(2), Some(4)]
+        );
+    }
+
+    /// Profile data of which Fn1 and Fn2 layers differ slightly from the
+    /// transparent and base layers respectively.
+    fn sample_profile_data() -> Vec<u8> {
+        let base: Vec<u16> = (0..LAYER_DATA_LEN / 2)
+            .map(|j| 0x0004 + u16::try_from(j % 0x60).unwrap())
+            .collect();
+        let mut fn1 = vec![TRANSPARENT_SCANCODE; LAYER_DATA_LEN / 2];
+        fn1[1] = 0x003a;
+        fn1[59] = 0x5102;
+        let mut fn2 = base.clone();
+        fn2[0] = 0x0035;
+        let fn3 = vec![TRANSPARENT_SCANCODE; LAYER_DATA_LEN / 2];
+        layers_to_profile_data(&[base, fn1, fn2, fn3]).unwrap()
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let data = sample_profile_data();
+        for serialized in [
+            serialize_to_toml_string(&data),
+            serialize_to_annotated_toml_string(&data, Layer::Base),
+            serialize_to_sparse_toml_string(&data),
+        ] {
+            let layers = parse_layers(&serialized).unwrap();
+            assert_eq!(
+                layers_to_profile_data(&layers).unwrap(),
+                data,
+                "{serialized}"
+            );
+        }
+    }
+
+    #[test]
+    fn annotated_toml_of_partial_layers() {
+        let data = sample_profile_data();
+        let serialized = serialize_to_annotated_toml_string(&data[LAYER_DATA_LEN..], Layer::Fn1);
+        assert!(serialized.starts_with("format_version = 1\n\n# Fn1\n[[layers]]\n"));
+        assert!(serialized.contains("\n# Fn3\n[[layers]]\n"));
+        let layers = parse_layers(&serialized).unwrap();
+        assert_eq!(
+            layers_to_profile_data(&layers).unwrap(),
+            data[LAYER_DATA_LEN..]
+        );
+    }
+
+    #[test]
+    fn sparse_toml_inherits_nearest_layer() {
+        let serialized = serialize_to_sparse_toml_string(&sample_profile_data());
+        let fn_layers: Vec<&str> = serialized.split("[[layers]]\n").skip(2).collect();
+        assert_eq!(
+            fn_layers,
+            [
+                "inherits = \"transparent\"\n[layers.keys]\n1 = 0x003a\nFn = 0x5102\n\n",
+                "inherits = \"base\"\n[layers.keys]\nEsc = 0x0035\n\n",
+                "inherits = \"transparent\"\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let data = sample_profile_data();
+        let serialized = serialize_to_json_string(&data);
+        assert_eq!(parse_json_string(&serialized).unwrap(), data);
+        let newer = serialized.replace("\"format_version\": 1", "\"format_version\": 2");
+        assert_eq!(
+            parse_json_string(&newer).unwrap_err().to_string(),
+            "format_version 2 is newer than the supported version 1"
+        );
+    }
+}
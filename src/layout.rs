@@ -1,8 +1,10 @@
+use std::borrow::Cow;
 use std::fmt::{Display, Write as _};
 use std::iter;
 
 /// Marker denoting a blank cell.
-const B: u8 = 0x80;
+pub const BLANK_CELL: u8 = 0x80;
+const B: u8 = BLANK_CELL;
 
 /// Physical layout of US keymap.
 #[rustfmt::skip]
@@ -17,6 +19,63 @@ pub const US_LAYOUT_WIDTHS_MAP: [[u8; 15]; 8] = [
     [75 | B, B, B, 5, 5, B, B, B, B, B, B, 5, 5, B, B], // gesture pad?
 ];
 
+/// Names of key positions in US keymap. Blank cells have empty names.
+#[rustfmt::skip]
+pub const US_POSITION_NAMES: [[&str; 15]; 8] = [
+    ["Esc", "1", "2", "3", "4", "5", "6", "7", "8", "9", "0", "Minus", "Equal", "Backslash", "Grave"],
+    ["Tab", "Q", "W", "E", "R", "T", "Y", "U", "I", "O", "P", "LBracket", "RBracket", "Delete", "Backspace"],
+    ["Control", "A", "S", "D", "F", "G", "H", "J", "K", "L", "Semicolon", "Quote", "", "", "Return"],
+    ["LShift", "Z", "X", "C", "V", "B", "N", "M", "Comma", "Period", "Slash", "", "", "RShift", "Fn"],
+    ["", "", "LAlt", "LMeta", "", "Space", "", "", "RMeta", "RAlt", "", "", "", "", ""],
+    ["", "", "", "", "MouseL", "MouseM", "MouseR", "", "", "", "", "Gesture1", "Gesture2", "", ""],
+    ["", "", "", "", "", "", "", "", "", "", "", "Gesture3", "Gesture4", "", ""],
+    ["", "", "", "Gesture5", "Gesture6", "", "", "", "", "", "", "Gesture7", "Gesture8", "", ""],
+];
+
+/// Returns the name of the key position at the `index` of layer scancodes.
+///
+/// Blank cells are named as `r<row>c<column>`.
+pub fn position_name(index: usize) -> Cow<'static, str> {
+    let (row, col) = (index / 15, index % 15);
+    match US_POSITION_NAMES[row][col] {
+        "" => format!("r{row}c{col}").into(),
+        name => name.into(),
+    }
+}
+
+/// Alternative names of key positions, after the keys at the same places of
+/// common keyboards.
+pub const POSITION_ALIASES: [(&str, &str); 1] = [("CapsLock", "Control")];
+
+/// Finds the index of the named key position. The name is compared ignoring
+/// case.
+pub fn position_index(name: &str) -> Option<usize> {
+    let name = POSITION_ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+        .map_or(name, |&(_, name)| name);
+    if let Some(index) = US_POSITION_NAMES
+        .iter()
+        .flatten()
+        .position(|n| !n.is_empty() && n.eq_ignore_ascii_case(name))
+    {
+        return Some(index);
+    }
+    let (row, col) = name.strip_prefix('r')?.split_once('c')?;
+    let (row, col): (usize, usize) = (row.parse().ok()?, col.parse().ok()?);
+    (row < US_POSITION_NAMES.len() && col < 15).then_some(row * 15 + col)
+}
+
+/// Describes the key position at the `index` of layer scancodes.
+pub fn describe_position(index: usize) -> String {
+    let (row, col) = (index / 15, index % 15);
+    match US_POSITION_NAMES.get(row).map(|names| names[col]) {
+        Some("") => format!("blank cell (row #{row}, column #{col})"),
+        Some(name) => format!("key {name} (row #{row}, column #{col})"),
+        None => format!("row #{row}, column #{col}"),
+    }
+}
+
 /// Formats the row `labels` based on the given `widths` layout table.
 pub fn format_row<I>(widths: &[u8], labels: I) -> String
 where
@@ -38,7 +97,7 @@ where
         }
         width -= 1;
         if is_blank {
-            line.extend(iter::repeat(' ').take(width));
+            line.extend(iter::repeat_n(' ', width));
         } else {
             let max_len = line.len() + width;
             write!(&mut line, "{label:width$}").unwrap();
@@ -1,4 +1,13 @@
 pub mod cli;
+mod document;
+mod edit_script;
+mod graph;
+mod hidraw;
+mod include;
 mod keymap;
 mod layout;
+mod lint;
+pub mod profile;
 mod scancode;
+mod schema;
+mod simulate;
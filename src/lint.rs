@@ -0,0 +1,249 @@
+//! Checker of common mistakes in profile data.
+
+use std::fmt;
+
+use crate::keymap::{self, Layer, PROFILE_DATA_LEN, TRANSPARENT_SCANCODE};
+use crate::{layout, scancode};
+
+/// Modifier keys expected in the base layer. Either of the left or right key
+/// should exist.
+const MODIFIERS: [(&str, [u16; 2]); 4] = [
+    ("Control", [0x00e0, 0x00e4]),
+    ("Shift", [0x00e1, 0x00e5]),
+    ("Alt", [0x00e2, 0x00e6]),
+    ("Meta", [0x00e3, 0x00e7]),
+];
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// Problem found in profile.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub layer: Layer,
+    /// Index of the key position in layer scancodes.
+    pub position: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{severity}: {}", self.layer)?;
+        if let Some(index) = self.position {
+            write!(f, ", {}", layout::describe_position(index))?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Checks profile data of all layers. Diagnostics are sorted by layer and
+/// position.
+pub fn lint_profile(profile_data: &[u8]) -> Vec<Diagnostic> {
+    assert_eq!(profile_data.len(), PROFILE_DATA_LEN);
+    let layers_scancodes = keymap::layers_scancodes(profile_data);
+    let mut diagnostics = Vec::new();
+    check_reachability(&layers_scancodes, &mut diagnostics);
+    check_scancodes(&layers_scancodes, &mut diagnostics);
+    check_modifiers(&layers_scancodes[0], &mut diagnostics);
+    check_identical_layers(&layers_scancodes, &mut diagnostics);
+    diagnostics.sort_by_key(|d| (d.layer.index(), d.position));
+    diagnostics
+}
+
+/// Returns true if the layer has any key other than transparent.
+fn is_layer_used(scancodes: &[u16]) -> bool {
+    scancodes.iter().any(|&code| code != TRANSPARENT_SCANCODE)
+}
+
+/// Reports Fn layers which are used but can't be activated from the base
+/// layer.
+fn check_reachability(layers_scancodes: &[Vec<u16>], diagnostics: &mut Vec<Diagnostic>) {
+    let reachable = keymap::reachable_layers(layers_scancodes);
+    for (i, scancodes) in layers_scancodes.iter().enumerate().skip(1) {
+        if reachable[i] || !is_layer_used(scancodes) {
+            continue;
+        }
+        let layer = Layer::from_index(i).unwrap();
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            layer,
+            position: None,
+            message: format!("layer is used but no {layer} key reaches it"),
+        });
+    }
+}
+
+/// Reports non-zero values in blank cells and scancodes without label.
+fn check_scancodes(layers_scancodes: &[Vec<u16>], diagnostics: &mut Vec<Diagnostic>) {
+    let blank_cells = layout::US_LAYOUT_WIDTHS_MAP
+        .iter()
+        .flatten()
+        .map(|&width| width & layout::BLANK_CELL != 0);
+    for (i, scancodes) in layers_scancodes.iter().enumerate() {
+        let layer = Layer::from_index(i).unwrap();
+        for (j, (&code, is_blank)) in scancodes.iter().zip(blank_cells.clone()).enumerate() {
+            let message = if code == TRANSPARENT_SCANCODE {
+                continue;
+            } else if is_blank {
+                format!("non-zero scancode 0x{code:04x} in blank cell")
+            } else if scancode::scancode_to_label(code).is_none() {
+                format!("unknown scancode 0x{code:04x}")
+            } else {
+                continue;
+            };
+            diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                layer,
+                position: Some(j),
+                message,
+            });
+        }
+    }
+}
+
+/// Reports modifier keys missing from the base layer.
+fn check_modifiers(base_scancodes: &[u16], diagnostics: &mut Vec<Diagnostic>) {
+    for (name, codes) in MODIFIERS {
+        if codes.iter().any(|code| base_scancodes.contains(code)) {
+            continue;
+        }
+        diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            layer: Layer::Base,
+            position: None,
+            message: format!("no {name} key"),
+        });
+    }
+}
+
+/// Reports used layers identical to the preceding layers.
+fn check_identical_layers(layers_scancodes: &[Vec<u16>], diagnostics: &mut Vec<Diagnostic>) {
+    for (i, scancodes) in layers_scancodes.iter().enumerate().skip(1) {
+        if !is_layer_used(scancodes) {
+            continue;
+        }
+        if let Some(k) = layers_scancodes[..i]
+            .iter()
+            .position(|other| other == scancodes)
+        {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                layer: Layer::from_index(i).unwrap(),
+                position: None,
+                message: format!("layer is identical to {}", Layer::from_index(k).unwrap()),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::LAYER_DATA_LEN;
+
+    fn pos(name: &str) -> usize {
+        layout::position_index(name).unwrap()
+    }
+
+    /// Layers of which base has modifiers, Fn and A keys, and Fn1 has Up on
+    /// Semicolon. Fn2 and Fn3 layers are unused.
+    fn clean_layers() -> Vec<Vec<u16>> {
+        let mut layers_scancodes = vec![vec![TRANSPARENT_SCANCODE; LAYER_DATA_LEN / 2]; 4];
+        for (name, code) in [
+            ("Control", 0x00e0),
+            ("LShift", 0x00e1),
+            ("LAlt", 0x00e2),
+            ("LMeta", 0x00e3),
+            ("RAlt", 0x00e6),
+            ("Fn", 0x5101),
+            ("A", 0x0004),
+        ] {
+            layers_scancodes[0][pos(name)] = code;
+        }
+        layers_scancodes[1][pos("Semicolon")] = 0x0052;
+        layers_scancodes
+    }
+
+    #[test]
+    fn lint_edited_profile() {
+        type Edit = fn(&mut [Vec<u16>]);
+        type Expected = Vec<(Severity, Layer, Option<usize>, &'static str)>;
+        let cases: Vec<(Edit, Expected)> = vec![
+            (|_| {}, vec![]),
+            // Fn key replaced, which would lock the user out of Fn1
+            (
+                |layers| layers[0][pos("Fn")] = 0x00e5,
+                vec![(
+                    Severity::Error,
+                    Layer::Fn1,
+                    None,
+                    "layer is used but no Fn1 key reaches it",
+                )],
+            ),
+            (
+                |layers| layers[2][pos("A")] = 0x0004,
+                vec![(
+                    Severity::Error,
+                    Layer::Fn2,
+                    None,
+                    "layer is used but no Fn2 key reaches it",
+                )],
+            ),
+            (
+                |layers| layers[1][pos("r2c12")] = 0x0004,
+                vec![(
+                    Severity::Warning,
+                    Layer::Fn1,
+                    Some(pos("r2c12")),
+                    "non-zero scancode 0x0004 in blank cell",
+                )],
+            ),
+            (
+                |layers| layers[1][pos("A")] = 0x7777,
+                vec![(
+                    Severity::Warning,
+                    Layer::Fn1,
+                    Some(pos("A")),
+                    "unknown scancode 0x7777",
+                )],
+            ),
+            // Either of the left or right key is enough
+            (|layers| layers[0][pos("LAlt")] = 0x0004, vec![]),
+            (
+                |layers| {
+                    layers[0][pos("LAlt")] = 0x0004;
+                    layers[0][pos("RAlt")] = 0x0004;
+                },
+                vec![(Severity::Warning, Layer::Base, None, "no Alt key")],
+            ),
+            (
+                |layers| layers[1] = layers[0].clone(),
+                vec![(
+                    Severity::Warning,
+                    Layer::Fn1,
+                    None,
+                    "layer is identical to Base",
+                )],
+            ),
+        ];
+        for (i, (edit, expected)) in cases.into_iter().enumerate() {
+            let mut layers = clean_layers();
+            edit(&mut layers);
+            let profile_data = keymap::layers_to_profile_data(&layers).unwrap();
+            let diagnostics = lint_profile(&profile_data);
+            let actual: Vec<_> = diagnostics
+                .iter()
+                .map(|d| (d.severity, d.layer, d.position, d.message.as_str()))
+                .collect();
+            assert_eq!(actual, expected, "case #{i}");
+        }
+    }
+}
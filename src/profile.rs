@@ -0,0 +1,346 @@
+//! Keymap profile which can be transformed in bulk.
+
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::keymap::{self, LAYER_DATA_LEN, TRANSPARENT_SCANCODE};
+use crate::layout;
+
+pub use crate::keymap::Layer;
+
+/// Number of key positions per row.
+pub const NUM_COLUMNS: usize = 15;
+/// Number of key positions per layer.
+pub const NUM_POSITIONS: usize = LAYER_DATA_LEN / 2;
+
+/// Rectangular region of key positions.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Region {
+    pub rows: Range<usize>,
+    pub columns: Range<usize>,
+}
+
+impl Region {
+    /// Creates region spanning the two corner positions (inclusive.)
+    pub fn from_corners(first: usize, last: usize) -> Self {
+        let (row1, col1) = (first / NUM_COLUMNS, first % NUM_COLUMNS);
+        let (row2, col2) = (last / NUM_COLUMNS, last % NUM_COLUMNS);
+        Region {
+            rows: row1.min(row2)..row1.max(row2) + 1,
+            columns: col1.min(col2)..col1.max(col2) + 1,
+        }
+    }
+
+    /// Returns the indices of the key positions in row-major order.
+    pub fn positions(&self) -> impl Iterator<Item = usize> + '_ {
+        self.rows
+            .clone()
+            .flat_map(|row| self.columns.clone().map(move |col| row * NUM_COLUMNS + col))
+    }
+}
+
+/// Parses two corner positions separated by ":" (e.g. "r1c11:r3c13".)
+impl FromStr for Region {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("expected two positions separated by \":\""))?;
+        let parse_position = |name: &str| {
+            layout::position_index(name)
+                .ok_or_else(|| anyhow::anyhow!("unknown key position: {name}"))
+        };
+        Ok(Region::from_corners(
+            parse_position(first)?,
+            parse_position(last)?,
+        ))
+    }
+}
+
+/// Scancodes of consecutive layers starting from the base layer.
+///
+/// Key positions are indexed as `row * 15 + column`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Profile {
+    layers: Vec<Vec<u16>>,
+}
+
+impl Profile {
+    pub fn from_data(profile_data: &[u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            !profile_data.is_empty() && profile_data.len().is_multiple_of(LAYER_DATA_LEN),
+            "unexpected profile data length"
+        );
+        let layers = keymap::layers_scancodes(profile_data);
+        Ok(Profile { layers })
+    }
+
+    pub fn to_data(&self) -> Vec<u8> {
+        self.layers
+            .iter()
+            .flatten()
+            .flat_map(|code| code.to_be_bytes())
+            .collect()
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn scancodes(&self, layer: Layer) -> anyhow::Result<&[u16]> {
+        self.layers
+            .get(layer.index())
+            .map(|scancodes| scancodes.as_slice())
+            .ok_or_else(|| anyhow::anyhow!("no {layer} layer in profile"))
+    }
+
+    fn scancodes_mut(&mut self, layer: Layer) -> anyhow::Result<&mut [u16]> {
+        self.layers
+            .get_mut(layer.index())
+            .map(|scancodes| scancodes.as_mut_slice())
+            .ok_or_else(|| anyhow::anyhow!("no {layer} layer in profile"))
+    }
+
+    /// Returns the specified layers, or all layers if `layers` is empty.
+    fn select_layers(&self, layers: &[Layer]) -> Vec<Layer> {
+        if layers.is_empty() {
+            (0..self.layers.len())
+                .map_while(Layer::from_index)
+                .collect()
+        } else {
+            layers.to_vec()
+        }
+    }
+
+    pub fn set_scancode(&mut self, layer: Layer, index: usize, code: u16) -> anyhow::Result<()> {
+        assert!(index < NUM_POSITIONS);
+        self.scancodes_mut(layer)?[index] = code;
+        Ok(())
+    }
+
+    /// Swaps the scancodes of the two key positions in the `layers` (or all
+    /// layers if empty.)
+    pub fn swap_keys(&mut self, layers: &[Layer], a: usize, b: usize) -> anyhow::Result<()> {
+        assert!(a < NUM_POSITIONS && b < NUM_POSITIONS);
+        for layer in self.select_layers(layers) {
+            self.scancodes_mut(layer)?.swap(a, b);
+        }
+        Ok(())
+    }
+
+    /// Copies the scancodes in the `region` of the `src` layer to the `dest`
+    /// layer. The region is placed at the same position unless the top-left
+    /// `dest_position` is specified.
+    pub fn copy_region(
+        &mut self,
+        src: Layer,
+        dest: Layer,
+        region: &Region,
+        dest_position: Option<usize>,
+    ) -> anyhow::Result<()> {
+        let num_rows = NUM_POSITIONS / NUM_COLUMNS;
+        anyhow::ensure!(
+            region.rows.end <= num_rows && region.columns.end <= NUM_COLUMNS,
+            "region out of range"
+        );
+        let (dest_row, dest_col) = dest_position
+            .map_or((region.rows.start, region.columns.start), |index| {
+                (index / NUM_COLUMNS, index % NUM_COLUMNS)
+            });
+        anyhow::ensure!(
+            dest_row + region.rows.len() <= num_rows
+                && dest_col + region.columns.len() <= NUM_COLUMNS,
+            "destination region out of range"
+        );
+        let src_scancodes = self.scancodes(src)?.to_vec();
+        let dest_scancodes = self.scancodes_mut(dest)?;
+        for (i, row) in region.rows.clone().enumerate() {
+            for (j, col) in region.columns.clone().enumerate() {
+                let code = src_scancodes[row * NUM_COLUMNS + col];
+                dest_scancodes[(dest_row + i) * NUM_COLUMNS + dest_col + j] = code;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills the layer with transparent scancodes.
+    pub fn clear_layer(&mut self, layer: Layer) -> anyhow::Result<()> {
+        self.scancodes_mut(layer)?.fill(TRANSPARENT_SCANCODE);
+        Ok(())
+    }
+
+    /// Replaces all occurrences of the scancode `from` with `to` in the
+    /// `layers` (or all layers if empty.) Returns the number of replaced keys.
+    pub fn replace_scancode(
+        &mut self,
+        layers: &[Layer],
+        from: u16,
+        to: u16,
+    ) -> anyhow::Result<usize> {
+        let mut count = 0;
+        for layer in self.select_layers(layers) {
+            for code in self.scancodes_mut(layer)?.iter_mut() {
+                if *code == from {
+                    *code = to;
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(name: &str) -> usize {
+        layout::position_index(name).unwrap()
+    }
+
+    /// Profile of which base layer is numbered by key positions, and Fn1 layer
+    /// is transparent.
+    fn numbered_profile() -> Profile {
+        let base = (0..NUM_POSITIONS)
+            .map(|j| u16::try_from(j).unwrap())
+            .collect();
+        let fn1 = vec![TRANSPARENT_SCANCODE; NUM_POSITIONS];
+        Profile {
+            layers: vec![base, fn1],
+        }
+    }
+
+    #[test]
+    fn region_from_reversed_corners() {
+        let expected = Region {
+            rows: 0..3,
+            columns: 1..4,
+        };
+        assert_eq!(Region::from_corners(pos("r0c1"), pos("r2c3")), expected);
+        assert_eq!(Region::from_corners(pos("r2c3"), pos("r0c1")), expected);
+        assert_eq!(Region::from_corners(pos("r0c3"), pos("r2c1")), expected);
+        assert_eq!("D:1".parse::<Region>().unwrap(), expected);
+        assert_eq!(
+            Region::from_corners(pos("A"), pos("A"))
+                .positions()
+                .collect::<Vec<_>>(),
+            [pos("A")]
+        );
+    }
+
+    #[test]
+    fn region_from_invalid_str() {
+        let err = "r0c1".parse::<Region>().unwrap_err();
+        assert_eq!(err.to_string(), "expected two positions separated by \":\"");
+        let err = "r0c1:Nowhere".parse::<Region>().unwrap_err();
+        assert_eq!(err.to_string(), "unknown key position: Nowhere");
+    }
+
+    #[test]
+    fn copy_region_to_same_position() {
+        let mut profile = numbered_profile();
+        let region: Region = "Q:E".parse().unwrap();
+        profile
+            .copy_region(Layer::Base, Layer::Fn1, &region, None)
+            .unwrap();
+        let fn1 = profile.scancodes(Layer::Fn1).unwrap();
+        for (j, &code) in fn1.iter().enumerate() {
+            let expected = if region.positions().any(|k| k == j) {
+                u16::try_from(j).unwrap()
+            } else {
+                TRANSPARENT_SCANCODE
+            };
+            assert_eq!(code, expected, "{}", layout::position_name(j));
+        }
+    }
+
+    #[test]
+    fn copy_region_at_position() {
+        let mut profile = numbered_profile();
+        let region: Region = "Q:S".parse().unwrap();
+        profile
+            .copy_region(Layer::Base, Layer::Fn1, &region, Some(pos("r3c13")))
+            .unwrap();
+        let fn1 = profile.scancodes(Layer::Fn1).unwrap();
+        let copied: Vec<_> = ["RShift", "Fn", "r4c13", "r4c14"]
+            .iter()
+            .map(|name| fn1[pos(name)])
+            .collect();
+        assert_eq!(
+            copied,
+            [pos("Q"), pos("W"), pos("A"), pos("S")].map(|j| j as u16)
+        );
+        assert_eq!(
+            fn1.iter()
+                .filter(|&&code| code != TRANSPARENT_SCANCODE)
+                .count(),
+            4
+        );
+    }
+
+    #[test]
+    fn copy_region_onto_itself() {
+        let mut profile = numbered_profile();
+        let region: Region = "Esc:Q".parse().unwrap();
+        profile
+            .copy_region(Layer::Base, Layer::Base, &region, Some(pos("1")))
+            .unwrap();
+        let base = profile.scancodes(Layer::Base).unwrap();
+        // The overlapping source keys are copied before overwritten
+        assert_eq!(
+            [
+                base[pos("1")],
+                base[pos("2")],
+                base[pos("Q")],
+                base[pos("W")]
+            ],
+            [pos("Esc"), pos("1"), pos("Tab"), pos("Q")].map(|j| j as u16)
+        );
+        assert_eq!(base[pos("Esc")], pos("Esc") as u16);
+    }
+
+    #[test]
+    fn copy_region_out_of_range() {
+        let mut profile = numbered_profile();
+        let region = Region {
+            rows: 7..9,
+            columns: 0..1,
+        };
+        let err = profile
+            .copy_region(Layer::Base, Layer::Fn1, &region, None)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "region out of range");
+        let region: Region = "Q:S".parse().unwrap();
+        let err = profile
+            .copy_region(Layer::Base, Layer::Fn1, &region, Some(pos("Grave")))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "destination region out of range");
+        let err = profile
+            .copy_region(Layer::Base, Layer::Fn2, &region, None)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no Fn2 layer in profile");
+        assert_eq!(profile, numbered_profile());
+    }
+
+    #[test]
+    fn replace_scancode_counts_keys() {
+        let mut profile = numbered_profile();
+        let count = profile
+            .replace_scancode(&[], TRANSPARENT_SCANCODE, 0x0004)
+            .unwrap();
+        // Esc of the base layer, and all keys of Fn1
+        assert_eq!(count, NUM_POSITIONS + 1);
+        let count = profile
+            .replace_scancode(&[Layer::Fn1], 0x0004, 0x0005)
+            .unwrap();
+        assert_eq!(count, NUM_POSITIONS);
+        assert_eq!(profile.scancodes(Layer::Base).unwrap()[4], 0x0004);
+        let count = profile.replace_scancode(&[Layer::Base], 0xffff, 0x0000);
+        assert_eq!(count.unwrap(), 0);
+        let err = profile
+            .replace_scancode(&[Layer::Fn2], 0x0004, 0x0005)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no Fn2 layer in profile");
+    }
+}
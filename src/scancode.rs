@@ -1,3 +1,21 @@
+/// Parses scancode from hexadecimal (0x...) or decimal number, or label.
+pub fn parse_scancode(s: &str) -> Option<u16> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Ok(n) = s.parse() {
+        Some(n)
+    } else {
+        label_to_scancode(s)
+    }
+}
+
+/// Finds the first scancode of the `label`. The label is compared ignoring
+/// case.
+pub fn label_to_scancode(label: &str) -> Option<u16> {
+    (0..=u16::MAX)
+        .find(|&code| scancode_to_label(code).is_some_and(|l| l.eq_ignore_ascii_case(label)))
+}
+
 /// Translates HHKB Studio scancode to short string label.
 pub fn scancode_to_label(code: u16) -> Option<&'static str> {
     match code {
@@ -0,0 +1,127 @@
+//! JSON Schema of TOML profile files.
+
+use std::collections::HashSet;
+
+use serde_json::json;
+
+use crate::keymap::{self, Layer, LAYER_DATA_LEN};
+use crate::{layout, scancode};
+
+/// Builds JSON Schema describing TOML profile files.
+///
+/// JSON profile files aren't covered since they only support the full
+/// `scancodes` arrays.
+///
+/// Key position names (and aliases) and scancode labels are taken from the
+/// tables used by the parser. Names are listed in canonical case, but the
+/// parser also accepts them in any case.
+pub fn profile_json_schema() -> serde_json::Value {
+    let layer_names: Vec<&str> = [Layer::Base, Layer::Fn1, Layer::Fn2, Layer::Fn3]
+        .iter()
+        .map(|layer| layer.name())
+        .chain(["transparent"])
+        .collect();
+    let mut seen_labels = HashSet::new();
+    let labels: Vec<&str> = (0..=u16::MAX)
+        .filter_map(scancode::scancode_to_label)
+        .filter(|label| seen_labels.insert(*label))
+        .collect();
+    let positions: serde_json::Map<String, serde_json::Value> = (0..LAYER_DATA_LEN / 2)
+        .map(|index| {
+            let name = layout::position_name(index).into_owned();
+            let description = layout::describe_position(index);
+            let schema = json!({
+                "$ref": "#/$defs/keyScancode",
+                "description": description,
+            });
+            (name, schema)
+        })
+        .chain(layout::POSITION_ALIASES.iter().map(|&(alias, name)| {
+            let schema = json!({
+                "$ref": "#/$defs/keyScancode",
+                "description": format!("alias of {name}"),
+            });
+            (alias.to_owned(), schema)
+        }))
+        .collect();
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "HHKB Studio keymap profile (TOML)",
+        "type": "object",
+        "properties": {
+            "format_version": {
+                "description": "Version of the profile file format (1 if omitted)",
+                "type": "integer",
+                "minimum": 1,
+                "maximum": keymap::FORMAT_VERSION,
+            },
+            "include": {
+                "description": "Files defining named layers, relative to this file",
+                "type": "array",
+                "items": { "type": "string" },
+            },
+            "metadata": {
+                "type": "object",
+                "additionalProperties": { "type": "string" },
+            },
+            "layers": {
+                "description": "Layers in order of Base, Fn1, Fn2, and Fn3",
+                "type": "array",
+                "items": { "$ref": "#/$defs/layer" },
+            },
+        },
+        "additionalProperties": false,
+        "$defs": {
+            "layer": {
+                "type": "object",
+                "properties": {
+                    "name": {
+                        "description": "Name by which other layers can inherit this layer",
+                        "type": "string",
+                        "not": { "enum": layer_names },
+                    },
+                    "scancodes": {
+                        "description": "Scancodes of all key positions, 15 per row",
+                        "type": "array",
+                        "items": { "$ref": "#/$defs/scancode" },
+                        "minItems": LAYER_DATA_LEN / 2,
+                        "maxItems": LAYER_DATA_LEN / 2,
+                    },
+                    "inherits": {
+                        "description": "Layer which fills the scancodes not in keys",
+                        "anyOf": [
+                            { "enum": layer_names },
+                            { "type": "string" },
+                        ],
+                    },
+                    "keys": {
+                        "description": "Scancodes overriding the inherited layer",
+                        "type": "object",
+                        "properties": positions,
+                        "patternProperties": {
+                            "^r[0-7]c([0-9]|1[0-4])$": { "$ref": "#/$defs/keyScancode" },
+                        },
+                        "additionalProperties": false,
+                    },
+                },
+                "additionalProperties": false,
+            },
+            "scancode": {
+                "type": "integer",
+                "minimum": 0,
+                "maximum": u16::MAX,
+            },
+            "keyScancode": {
+                "anyOf": [
+                    { "$ref": "#/$defs/scancode" },
+                    { "enum": labels },
+                    {
+                        "type": "string",
+                        "pattern": "^(0[xX][0-9a-fA-F]{1,4}|[0-9]+)$",
+                    },
+                ],
+            },
+        },
+    })
+}
@@ -0,0 +1,287 @@
+//! Simulator of key presses on profile.
+
+use std::fmt;
+
+use crate::keymap::{self, Layer, TRANSPARENT_SCANCODE};
+use crate::{layout, scancode};
+
+/// Key event given by the user.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyEvent {
+    Press(usize),
+    Release(usize),
+    /// Press and release
+    Tap(usize),
+}
+
+impl fmt::Display for KeyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (prefix, index) = match *self {
+            KeyEvent::Press(index) => ("+", index),
+            KeyEvent::Release(index) => ("-", index),
+            KeyEvent::Tap(index) => ("", index),
+        };
+        write!(f, "{prefix}{}", layout::position_name(index))
+    }
+}
+
+/// Event sent to the host, or layer change.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Output {
+    LayerOn(Layer),
+    LayerOff(Layer),
+    KeyDown(u16),
+    KeyUp(u16),
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (action, code) = match *self {
+            Output::LayerOn(layer) => return write!(f, "{layer} on"),
+            Output::LayerOff(layer) => return write!(f, "{layer} off"),
+            Output::KeyDown(code) => ("down", code),
+            Output::KeyUp(code) => ("up", code),
+        };
+        write!(f, "{action} 0x{code:04x}")?;
+        if let Some(label) = scancode::scancode_to_label(code) {
+            write!(f, " ({label})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Keyboard state driven by key events.
+///
+/// Fn keys activate the layer while held. Transparent keys fall through to
+/// the base layer. The scancode is resolved when the key is pressed, and the
+/// same scancode is released.
+#[derive(Clone, Debug)]
+pub struct Simulator<'a> {
+    layers_scancodes: &'a [Vec<u16>],
+    /// Held key positions and the scancodes resolved on press.
+    pressed: Vec<(usize, u16)>,
+}
+
+impl<'a> Simulator<'a> {
+    pub fn new(layers_scancodes: &'a [Vec<u16>]) -> Self {
+        Simulator {
+            layers_scancodes,
+            pressed: Vec::new(),
+        }
+    }
+
+    /// Returns the layer of the last held Fn key, or the base layer.
+    pub fn active_layer(&self) -> Layer {
+        self.pressed
+            .iter()
+            .rev()
+            .find_map(|&(_, code)| keymap::activated_layer(code))
+            .filter(|layer| layer.index() < self.layers_scancodes.len())
+            .unwrap_or(Layer::Base)
+    }
+
+    /// Returns the positions of the keys being held.
+    pub fn held_positions(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pressed.iter().map(|&(index, _)| index)
+    }
+
+    /// Processes the event. Returns the outputs in order.
+    pub fn handle_event(&mut self, event: KeyEvent) -> anyhow::Result<Vec<Output>> {
+        let mut outputs = Vec::new();
+        match event {
+            KeyEvent::Press(index) => outputs.extend(self.press(index)?),
+            KeyEvent::Release(index) => outputs.extend(self.release(index)?),
+            KeyEvent::Tap(index) => {
+                outputs.extend(self.press(index)?);
+                outputs.extend(self.release(index)?);
+            }
+        }
+        Ok(outputs)
+    }
+
+    fn press(&mut self, index: usize) -> anyhow::Result<Option<Output>> {
+        anyhow::ensure!(
+            self.pressed.iter().all(|&(j, _)| j != index),
+            "key {} is already pressed",
+            layout::position_name(index)
+        );
+        let layer = self.active_layer();
+        let code = keymap::effective_scancode(self.layers_scancodes, layer.index(), index);
+        self.pressed.push((index, code));
+        let output = match keymap::activated_layer(code) {
+            _ if code == TRANSPARENT_SCANCODE => None,
+            Some(to_layer) => Some(Output::LayerOn(to_layer)),
+            None => Some(Output::KeyDown(code)),
+        };
+        Ok(output)
+    }
+
+    fn release(&mut self, index: usize) -> anyhow::Result<Option<Output>> {
+        let pos = self
+            .pressed
+            .iter()
+            .position(|&(j, _)| j == index)
+            .ok_or_else(|| anyhow::anyhow!("key {} isn't pressed", layout::position_name(index)))?;
+        let (_, code) = self.pressed.remove(pos);
+        let output = match keymap::activated_layer(code) {
+            _ if code == TRANSPARENT_SCANCODE => None,
+            Some(to_layer) => Some(Output::LayerOff(to_layer)),
+            None => Some(Output::KeyUp(code)),
+        };
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::LAYER_DATA_LEN;
+
+    fn pos(name: &str) -> usize {
+        layout::position_index(name).unwrap()
+    }
+
+    /// Base layer with Fn key and a few letters, Fn1 layer with Fn2 key on A
+    /// and Up on Semicolon, and Fn2 layer with Down on Semicolon.
+    fn sample_layers() -> Vec<Vec<u16>> {
+        let mut layers_scancodes = vec![vec![TRANSPARENT_SCANCODE; LAYER_DATA_LEN / 2]; 3];
+        for (name, code) in [
+            ("Fn", 0x5101),
+            ("A", 0x0004),
+            ("S", 0x0016),
+            ("Semicolon", 0x0033),
+        ] {
+            layers_scancodes[0][pos(name)] = code;
+        }
+        layers_scancodes[1][pos("A")] = 0x5102;
+        layers_scancodes[1][pos("Semicolon")] = 0x0052;
+        layers_scancodes[2][pos("A")] = 0x5102;
+        layers_scancodes[2][pos("Semicolon")] = 0x0051;
+        layers_scancodes
+    }
+
+    fn run_events(simulator: &mut Simulator, events: &[KeyEvent]) -> Vec<Output> {
+        events
+            .iter()
+            .flat_map(|&event| simulator.handle_event(event).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn press_release_tap() {
+        let layers_scancodes = sample_layers();
+        let mut simulator = Simulator::new(&layers_scancodes);
+        let outputs = run_events(
+            &mut simulator,
+            &[
+                KeyEvent::Press(pos("A")),
+                KeyEvent::Tap(pos("S")),
+                KeyEvent::Release(pos("A")),
+            ],
+        );
+        assert_eq!(
+            outputs,
+            [
+                Output::KeyDown(0x0004),
+                Output::KeyDown(0x0016),
+                Output::KeyUp(0x0016),
+                Output::KeyUp(0x0004),
+            ]
+        );
+        assert_eq!(simulator.held_positions().count(), 0);
+    }
+
+    #[test]
+    fn nested_fn_layers() {
+        let layers_scancodes = sample_layers();
+        let mut simulator = Simulator::new(&layers_scancodes);
+        let outputs = run_events(
+            &mut simulator,
+            &[KeyEvent::Press(pos("Fn")), KeyEvent::Press(pos("A"))],
+        );
+        assert_eq!(
+            outputs,
+            [Output::LayerOn(Layer::Fn1), Output::LayerOn(Layer::Fn2)]
+        );
+        assert_eq!(simulator.active_layer(), Layer::Fn2);
+        let outputs = run_events(
+            &mut simulator,
+            &[
+                KeyEvent::Tap(pos("Semicolon")),
+                KeyEvent::Release(pos("A")),
+                KeyEvent::Tap(pos("Semicolon")),
+                KeyEvent::Release(pos("Fn")),
+            ],
+        );
+        assert_eq!(
+            outputs,
+            [
+                Output::KeyDown(0x0051),
+                Output::KeyUp(0x0051),
+                Output::LayerOff(Layer::Fn2),
+                Output::KeyDown(0x0052),
+                Output::KeyUp(0x0052),
+                Output::LayerOff(Layer::Fn1),
+            ]
+        );
+        assert_eq!(simulator.active_layer(), Layer::Base);
+    }
+
+    #[test]
+    fn transparent_key_falls_through_to_base() {
+        let layers_scancodes = sample_layers();
+        let mut simulator = Simulator::new(&layers_scancodes);
+        let outputs = run_events(
+            &mut simulator,
+            &[KeyEvent::Press(pos("Fn")), KeyEvent::Tap(pos("S"))],
+        );
+        assert_eq!(
+            outputs,
+            [
+                Output::LayerOn(Layer::Fn1),
+                Output::KeyDown(0x0016),
+                Output::KeyUp(0x0016),
+            ]
+        );
+    }
+
+    #[test]
+    fn release_scancode_resolved_on_press() {
+        let layers_scancodes = sample_layers();
+        let mut simulator = Simulator::new(&layers_scancodes);
+        let outputs = run_events(
+            &mut simulator,
+            &[
+                KeyEvent::Press(pos("Semicolon")),
+                KeyEvent::Press(pos("Fn")),
+                KeyEvent::Release(pos("Semicolon")),
+            ],
+        );
+        assert_eq!(
+            outputs,
+            [
+                Output::KeyDown(0x0033),
+                Output::LayerOn(Layer::Fn1),
+                Output::KeyUp(0x0033),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_press_and_release() {
+        let layers_scancodes = sample_layers();
+        let mut simulator = Simulator::new(&layers_scancodes);
+        simulator.handle_event(KeyEvent::Press(pos("A"))).unwrap();
+        let err = simulator
+            .handle_event(KeyEvent::Press(pos("A")))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "key A is already pressed");
+        let err = simulator
+            .handle_event(KeyEvent::Release(pos("S")))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "key S isn't pressed");
+        let err = simulator.handle_event(KeyEvent::Tap(pos("A"))).unwrap_err();
+        assert_eq!(err.to_string(), "key A is already pressed");
+        assert_eq!(simulator.held_positions().collect::<Vec<_>>(), [pos("A")]);
+    }
+}